@@ -7,13 +7,20 @@ pub struct Adsr{
     dec_value: f32,
     sus_value: f32,
     rel_value: f32,
+    delay_samples: u32,
+    hold_samples: u32,
+    stage_counter: u32,
     sample_rate: f32,
+    /// Release step of a quick release, used instead of `rel_step` until the next note on
+    quick_rel_step: Option<f32>,
     pub envelope_value: f32,
     pub state: AdsrState,
 }
 #[derive(PartialEq, Debug, Clone)]
 pub enum AdsrState{
+    Delay,
     Attack,
+    Hold,
     Decay,
     Sustain,
     Release,
@@ -32,7 +39,11 @@ impl Adsr{
             dec_value: 0.1,
             sus_value: 1.0,
             rel_value: 0.1,
+            delay_samples: 0,
+            hold_samples: 0,
+            stage_counter: 0,
             sample_rate: sample_rate_,
+            quick_rel_step: None,
             envelope_value: 0.0,
             state: AdsrState::Inactive,
         };
@@ -44,6 +55,13 @@ impl Adsr{
     pub fn get_next_sample(&mut self)->f32{
         match self.state{
             AdsrState::Inactive => 0.0,
+            AdsrState::Delay => {
+                self.stage_counter += 1;
+                if self.stage_counter >= self.delay_samples{
+                    self.get_next_state();
+                }
+                self.envelope_value
+            },
             AdsrState::Attack => {
                 self.envelope_value += self.atk_step;
                 if self.envelope_value >= 1.0{
@@ -52,6 +70,13 @@ impl Adsr{
                 }
                 self.envelope_value
             },
+            AdsrState::Hold => {
+                self.stage_counter += 1;
+                if self.stage_counter >= self.hold_samples{
+                    self.get_next_state();
+                }
+                self.envelope_value
+            },
             AdsrState::Decay => {
                 self.envelope_value -= self.dec_step;
                 if self.envelope_value <= self.sus_value{
@@ -64,7 +89,7 @@ impl Adsr{
                 self.sus_value
             },
             AdsrState::Release => {
-                self.envelope_value -= self.rel_step;
+                self.envelope_value -= self.quick_rel_step.unwrap_or(self.rel_step);
                 if self.envelope_value <= 0.0{
                     self.envelope_value = 0.0;
                     self.get_next_state();
@@ -73,6 +98,14 @@ impl Adsr{
             }
         } 
     }
+    /// Sets the time in seconds before the attack starts
+    pub fn set_delay(&mut self, delay_:f32){
+        self.delay_samples = (delay_.max(0.0) * self.sample_rate) as u32;
+    }
+    /// Sets the time in seconds the envelope holds at full level between attack and decay
+    pub fn set_hold(&mut self, hold_:f32){
+        self.hold_samples = (hold_.max(0.0) * self.sample_rate) as u32;
+    }
     /// Sets attack in seconds
     pub fn set_attack(&mut self, attack_:f32){
 
@@ -116,16 +149,28 @@ impl Adsr{
     pub fn get_adsr(&mut self)->(f32, f32, f32, f32){
         (self.atk_value, self.dec_value, self.sus_value, self. rel_value)
     }
-    /// Triggers the attack stage of the ADSR
+    /// Triggers the attack stage of the ADSR, after the delay if there is one
     pub fn note_on(&mut self){
-        self.state = AdsrState::Attack;
+        self.stage_counter = 0;
+        self.quick_rel_step = None;
+        if self.delay_samples > 0{
+            self.state = AdsrState::Delay;
+        }else{
+            self.state = AdsrState::Attack;
+        }
     } 
     /// Triggers the release stage of the ADSR
     pub fn note_off(&mut self){
         self.state = AdsrState::Release;
 
     }
-    /// Returns whether or not the ADSR is active (in any of the states: A, D, S, or R)
+    /// Releases from full level to silence in `release_` seconds instead of the set release.
+    /// Only this note is affected, the next note on goes back to the set release
+    pub fn quick_release(&mut self, release_:f32){
+        self.quick_rel_step = Some(self.get_step(1.0, release_.max(0.00001)));
+        self.note_off();
+    }
+    /// Returns whether or not the ADSR is active (in any of the states: Delay, A, Hold, D, S, or R)
     pub fn is_active(&mut self)-> bool{
         self.state != AdsrState::Inactive
     }
@@ -142,7 +187,20 @@ impl Adsr{
     fn get_next_state(&mut self){
         match self.state{
             AdsrState::Inactive => self.state = AdsrState::Inactive,
+            AdsrState::Delay => {
+                self.state = AdsrState::Attack
+            },
             AdsrState::Attack => {
+                self.stage_counter = 0;
+                if self.hold_samples > 0{
+                    self.state = AdsrState::Hold;
+                }else if self.dec_value > 0.0{
+                    self.state = AdsrState::Decay;
+                }else{
+                    self.state = AdsrState::Sustain;
+                }
+            },
+            AdsrState::Hold => {
                 if self.dec_value > 0.0{
                     self.state = AdsrState::Decay;
                 }else{
@@ -161,6 +219,7 @@ impl Adsr{
         } 
     }
     pub fn reset(&mut self){
+        self.quick_rel_step = None;
         self.state = AdsrState::Inactive;
         self.envelope_value = 0.0;
     }
@@ -217,5 +276,38 @@ mod tests{
             }
         }
     }
+    #[test]
+    fn test_delay_and_hold(){
+        let mut adsr = Adsr::new(10.0, 0.2, 0.1, 0.5, 0.2);
+        adsr.set_delay(0.3);
+        adsr.set_hold(0.2);
+        adsr.note_on();
+        let envelope: Vec<f32> = (0..9).map(|_| adsr.get_next_sample()).collect();
+        assert_eq!(&envelope[..3], &[0.0, 0.0, 0.0]);
+        assert_close!(envelope[3], 0.5, 0.001);
+        assert_close!(envelope[4], 1.0, 0.001);
+        assert_eq!(&envelope[5..7], &[1.0, 1.0]);
+        assert_close!(envelope[7], 0.5, 0.001);
+        assert_eq!(adsr.state, AdsrState::Sustain);
+    }
+    #[test]
+    fn test_quick_release(){
+        let mut adsr = Adsr::new(10.0, 0.2, 0.1, 0.5, 0.2);
+        adsr.note_on();
+        for _ in 0..10{
+            adsr.get_next_sample();
+        }
+        adsr.quick_release(0.1);
+        assert_eq!(adsr.get_next_sample(), 0.0);
+        assert_eq!(adsr.state, AdsrState::Inactive);
+        // The next note releases with the set release again
+        assert_close!(adsr.get_adsr().3, 0.2, 0.001);
+        adsr.note_on();
+        for _ in 0..10{
+            adsr.get_next_sample();
+        }
+        adsr.note_off();
+        assert_close!(adsr.get_next_sample(), 0.25, 0.001);
+    }
 }
 
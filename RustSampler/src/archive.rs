@@ -0,0 +1,355 @@
+use crate::{sampler_engine,sfz,decoder};
+use sampler_engine::create_buffer_from_bytes;
+use sfz::{SfzInstrument, SfzLoopMode, SfzRegion, SfzTrigger};
+use roxmltree::{Document, Node};
+use std::{collections::HashMap, io::{self, Read, Seek}, sync::Arc};
+use zip::ZipArchive;
+
+/// The mapping file inside a Bitwig `.multisample` archive
+const MULTISAMPLE_XML: &str = "multisample.xml";
+/// The mapping file inside a Renoise `.xrni` archive
+const XRNI_XML: &str = "Instrument.xml";
+/// The folder of a Renoise archive that holds the sample audio
+const XRNI_SAMPLE_DIR: &str = "SampleData/";
+
+/// Reads a Bitwig `.multisample` archive and decodes the samples packed inside it
+pub fn read_multisample<R: Read + Seek>(reader: R)->io::Result<SfzInstrument>{
+    let mut archive = ZipArchive::new(reader)?;
+    let text = read_text(&mut archive, MULTISAMPLE_XML)?;
+    let (regions, sample_names, errors) = parse_multisample(&text)?;
+    Ok(load_samples(&mut archive, regions, sample_names, errors))
+}
+
+/// Reads a Renoise `.xrni` instrument archive and decodes the samples packed inside it
+pub fn read_xrni<R: Read + Seek>(reader: R)->io::Result<SfzInstrument>{
+    let mut archive = ZipArchive::new(reader)?;
+    let text = read_text(&mut archive, XRNI_XML)?;
+    let (regions, sample_indices, errors) = parse_xrni(&text)?;
+    // Renoise names the audio of the nth sample "SampleData/SampleNN (name).ext"
+    let mut sample_files = HashMap::new();
+    for name in archive.file_names(){
+        let index = name.strip_prefix(XRNI_SAMPLE_DIR)
+            .and_then(|file| file.strip_prefix("Sample"))
+            .map(|file| file.chars().take_while(char::is_ascii_digit).collect::<String>())
+            .and_then(|digits| digits.parse::<usize>().ok());
+        if let Some(index) = index{
+            sample_files.insert(index, name.to_string());
+        }
+    }
+    let sample_names = sample_indices.iter()
+        .map(|index| sample_files.get(index).cloned().unwrap_or_else(|| format!("{}Sample{:02}", XRNI_SAMPLE_DIR, index)))
+        .collect();
+    Ok(load_samples(&mut archive, regions, sample_names, errors))
+}
+
+/// Reads the zones of a `multisample.xml` mapping, with the archive path of each
+/// zone's sample and the problems found along the way.
+///
+/// Zones marked as round robin that cover the same keys and velocities take turns
+pub fn parse_multisample(text: &str)->io::Result<(Vec<SfzRegion>, Vec<String>, Vec<String>)>{
+    let document = parse_document(text, "multisample")?;
+    let mut regions = Vec::new();
+    let mut sample_names = Vec::new();
+    let mut errors = Vec::new();
+    let mut round_robins = HashMap::<(u8, u8, u8, u8), Vec<usize>>::new();
+    for sample in document.root_element().descendants().filter(|node| node.has_tag_name("sample")){
+        let Some(file) = sample.attribute("file") else{
+            errors.push("Sample zone without a file".to_string());
+            continue;
+        };
+        let mut region = SfzRegion::default();
+        if let Some(key) = child(sample, "key"){
+            region.pitch_keycenter = attribute(key, "root").map_or(60, |root: f32| root.clamp(0.0, 127.0) as u8);
+            region.pitch_keytrack = 100.0 * attribute(key, "track").unwrap_or(1.0);
+            region.tune = 100.0 * attribute(key, "tune").unwrap_or(0.0);
+            (region.lokey, region.hikey, region.xfin_key, region.xfout_key) = zone_range(key);
+        }
+        if let Some(velocity) = child(sample, "velocity"){
+            (region.lovel, region.hivel, region.xfin_vel, region.xfout_vel) = zone_range(velocity);
+        }
+        region.volume = attribute(sample, "gain").unwrap_or(0.0);
+        region.offset = attribute(sample, "sample-start").map_or(0, |frame: f64| frame.max(0.0) as usize);
+        // Bitwig's stop positions are the frame after the last one played, sfz's are the last one
+        region.end = attribute(sample, "sample-stop").map(last_frame);
+        if let Some(sample_loop) = child(sample, "loop"){
+            region.loop_mode = match sample_loop.attribute("mode"){
+                // Ping-pong loops are played forwards
+                Some("loop") | Some("ping-pong") => Some(SfzLoopMode::LoopContinuous),
+                _ => Some(SfzLoopMode::NoLoop),
+            };
+            region.loop_start = attribute(sample_loop, "start").map(|frame: f64| frame.max(0.0) as usize);
+            region.loop_end = attribute(sample_loop, "stop").map(last_frame);
+        }
+        if sample.attribute("reverse") == Some("true"){
+            errors.push(format!("Reversed sample {} is played forwards", file));
+        }
+        if sample.attribute("zone-logic") == Some("round-robin"){
+            round_robins.entry((region.lokey, region.hikey, region.lovel, region.hivel))
+                .or_default()
+                .push(regions.len());
+        }
+        regions.push(region);
+        sample_names.push(file.to_string());
+    }
+    // Group 0 holds the zones that always play, so each round robin gets a group after it
+    let mut round_robins = round_robins.into_values().collect::<Vec<_>>();
+    round_robins.sort();
+    for (group, zones) in round_robins.iter().enumerate(){
+        for (position, zone) in zones.iter().enumerate(){
+            regions[*zone].seq_group = group + 1;
+            regions[*zone].seq_length = zones.len() as u32;
+            regions[*zone].seq_position = position as u32 + 1;
+        }
+    }
+    Ok((regions, sample_names, errors))
+}
+
+/// Reads the samples of an `Instrument.xml` mapping, with the index of each
+/// sample in the instrument and the problems found along the way
+pub fn parse_xrni(text: &str)->io::Result<(Vec<SfzRegion>, Vec<usize>, Vec<String>)>{
+    let document = parse_document(text, "RenoiseInstrument")?;
+    let mut regions = Vec::new();
+    let mut sample_indices = Vec::new();
+    let mut errors = Vec::new();
+    let samples = document.root_element()
+        .descendants()
+        .find(|node| node.has_tag_name("Samples"))
+        .into_iter()
+        .flat_map(|samples| samples.children().filter(|node| node.has_tag_name("Sample")));
+    for (index, sample) in samples.enumerate(){
+        let mut region = SfzRegion{
+            // Renoise gains are linear and its panning goes from 0 (left) to 1 (right)
+            volume: 20.0 * child_value(sample, "Volume").unwrap_or(1.0_f32).max(1e-6).log10(),
+            pan: (child_value(sample, "Panning").unwrap_or(0.5_f32) - 0.5).clamp(-0.5, 0.5) * 200.0,
+            transpose: child_value(sample, "Transpose").unwrap_or(0.0),
+            // Finetune steps are 1/128 of a semitone
+            tune: child_value(sample, "Finetune").unwrap_or(0.0_f32) * 100.0 / 128.0,
+            ..SfzRegion::default()
+        };
+        let loop_release = child_text(sample, "LoopRelease") == Some("true");
+        region.loop_mode = match child_text(sample, "LoopMode"){
+            Some("Off") | None => Some(SfzLoopMode::NoLoop),
+            Some(mode) =>{
+                if mode != "Forward"{
+                    errors.push(format!("{} loop of sample {} is played forwards", mode, index));
+                }
+                if loop_release {Some(SfzLoopMode::LoopSustain)} else {Some(SfzLoopMode::LoopContinuous)}
+            }
+        };
+        region.loop_start = child_value(sample, "LoopStart");
+        region.loop_end = child_value(sample, "LoopEnd");
+        if let Some(mapping) = child(sample, "Mapping"){
+            if child_text(mapping, "Layer").is_some_and(|layer| layer.contains("Off")){
+                region.trigger = SfzTrigger::Release;
+            }
+            region.pitch_keycenter = child_value(mapping, "BaseNote").unwrap_or(48_u8).min(127);
+            region.lokey = child_value(mapping, "NoteStart").unwrap_or(0_u8).min(127);
+            region.hikey = child_value(mapping, "NoteEnd").unwrap_or(119_u8).min(127);
+            region.lovel = child_value(mapping, "VelocityStart").unwrap_or(0_u8).min(127);
+            region.hivel = child_value(mapping, "VelocityEnd").unwrap_or(127_u8).min(127);
+            if child_text(mapping, "MapKeyToPitch") == Some("false"){
+                region.pitch_keytrack = 0.0;
+            }
+            if child_text(mapping, "MapVelocityToVolume") == Some("false"){
+                region.amp_veltrack = 0.0;
+            }
+        }
+        regions.push(region);
+        sample_indices.push(index);
+    }
+    Ok((regions, sample_indices, errors))
+}
+
+/// Decodes the sample of each region from the archive. Regions that share a file share
+/// the decoded buffer, and regions whose sample can't be read are skipped
+fn load_samples<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    regions: Vec<SfzRegion>,
+    sample_names: Vec<String>,
+    mut errors: Vec<String>,
+)->SfzInstrument{
+    let mut samples = Vec::new();
+    let mut sample_ids = HashMap::<String, usize>::new();
+    let mut missing_samples = Vec::new();
+    let mut loaded_regions = Vec::with_capacity(regions.len());
+    for (mut region, name) in regions.into_iter().zip(sample_names){
+        let name = name.replace('\\', "/");
+        region.sample = match sample_ids.get(&name){
+            Some(id) => *id,
+            None =>{
+                let data = match read_bytes(archive, &name){
+                    Ok(data) => data,
+                    Err(_) =>{
+                        if !missing_samples.contains(&name){
+                            missing_samples.push(name);
+                        }
+                        continue;
+                    }
+                };
+                if !decoder::is_supported(&name){
+                    errors.push(format!("Unsupported sample format: {}", name));
+                    sample_ids.insert(name, usize::MAX);
+                    continue;
+                }
+                match create_buffer_from_bytes(&data, &name){
+                    Ok((buffer, _sample_rate)) =>{
+                        samples.push(Arc::new(buffer));
+                        sample_ids.insert(name, samples.len() - 1);
+                        samples.len() - 1
+                    },
+                    Err(e) =>{
+                        errors.push(format!("Could not decode {}: {}", name, e));
+                        sample_ids.insert(name, usize::MAX);
+                        continue;
+                    }
+                }
+            }
+        };
+        if region.sample != usize::MAX{
+            loaded_regions.push(region);
+        }
+    }
+    SfzInstrument::from_regions(loaded_regions, samples).with_problems(errors, missing_samples)
+}
+
+fn read_bytes<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str)->io::Result<Vec<u8>>{
+    let mut file = archive.by_name(name)?;
+    let mut data = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn read_text<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str)->io::Result<String>{
+    String::from_utf8(read_bytes(archive, name)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Parses an XML mapping and checks the name of its root element
+fn parse_document<'a>(text: &'a str, root: &str)->io::Result<Document<'a>>{
+    let document = Document::parse(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    if !document.root_element().has_tag_name(root){
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("missing <{}> element", root)));
+    }
+    Ok(document)
+}
+
+/// Reads the low, high and fade attributes of a Bitwig key or velocity zone as a
+/// range and the crossfade ranges at either end of it
+fn zone_range(zone: Node)->(u8, u8, (u8, u8), (u8, u8)){
+    let low = attribute(zone, "low").map_or(0, |low: f32| low.clamp(0.0, 127.0) as u8);
+    let high = attribute(zone, "high").map_or(127, |high: f32| high.clamp(0.0, 127.0) as u8);
+    let low_fade = attribute(zone, "low-fade").map_or(0, |fade: f32| fade.clamp(0.0, 127.0) as u8);
+    let high_fade = attribute(zone, "high-fade").map_or(0, |fade: f32| fade.clamp(0.0, 127.0) as u8);
+    let fade_in = if low_fade == 0 {(0, 0)} else {(low, low.saturating_add(low_fade).min(high))};
+    let fade_out = if high_fade == 0 {(127, 127)} else {(high.saturating_sub(high_fade).max(low), high)};
+    (low, high, fade_in, fade_out)
+}
+
+/// Turns an exclusive stop position into the last frame played
+fn last_frame(stop: f64)->usize{
+    (stop.max(0.0) as usize).saturating_sub(1)
+}
+
+fn attribute<T: std::str::FromStr>(node: Node, name: &str)->Option<T>{
+    node.attribute(name).and_then(|value| value.trim().parse().ok())
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str)->Option<Node<'a, 'input>>{
+    node.children().find(|child| child.has_tag_name(name))
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &str)->Option<&'a str>{
+    child(node, name).and_then(|child| child.text()).map(str::trim)
+}
+
+fn child_value<T: std::str::FromStr>(node: Node, name: &str)->Option<T>{
+    child_text(node, name).and_then(|text| text.parse().ok())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::io::{Cursor, Write};
+    #[test]
+    fn test_multisample(){
+        let text = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <multisample name="Keys">
+              <generator>Bitwig Studio</generator>
+              <group name="Soft"/>
+              <sample file="a.wav" gain="-3.0" sample-start="10.000" sample-stop="90.000">
+                <key root="60" track="1.0" tune="0.5" low="48" high="72" low-fade="4" high-fade="0"/>
+                <velocity low="0" high="100" high-fade="20"/>
+                <loop mode="loop" start="20.000" stop="80.000"/>
+              </sample>
+              <sample file="b.wav" zone-logic="round-robin"><key root="40" low="40" high="40"/></sample>
+              <sample file="c.wav" zone-logic="round-robin"><key root="40" low="40" high="40"/></sample>
+            </multisample>"#;
+        let (regions, names, errors) = parse_multisample(text).unwrap();
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(names, vec!["a.wav", "b.wav", "c.wav"]);
+        let a = &regions[0];
+        assert_eq!((a.lokey, a.hikey, a.pitch_keycenter, a.tune, a.volume), (48, 72, 60, 50.0, -3.0));
+        assert_eq!((a.xfin_key, a.xfout_key, a.xfout_vel), ((48, 52), (127, 127), (80, 100)));
+        assert_eq!((a.offset, a.end, a.loop_start, a.loop_end), (10, Some(89), Some(20), Some(79)));
+        assert_eq!(a.loop_mode, Some(SfzLoopMode::LoopContinuous));
+        assert_eq!((a.seq_group, a.seq_length), (0, 1));
+        assert_eq!((regions[1].seq_group, regions[1].seq_length, regions[1].seq_position), (1, 2, 1));
+        assert_eq!((regions[2].seq_group, regions[2].seq_length, regions[2].seq_position), (1, 2, 2));
+    }
+    #[test]
+    fn test_xrni(){
+        let text = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <RenoiseInstrument doc_version="22">
+              <Name>Bass</Name>
+              <SampleGenerator>
+                <Samples>
+                  <Sample>
+                    <Name>Low</Name>
+                    <Volume>0.5</Volume>
+                    <Panning>0.75</Panning>
+                    <Finetune>64</Finetune>
+                    <LoopMode>Forward</LoopMode>
+                    <LoopStart>2</LoopStart>
+                    <LoopEnd>6</LoopEnd>
+                    <Mapping>
+                      <Layer>Note-On</Layer>
+                      <BaseNote>36</BaseNote>
+                      <NoteStart>0</NoteStart>
+                      <NoteEnd>47</NoteEnd>
+                    </Mapping>
+                  </Sample>
+                  <Sample>
+                    <Name>Release</Name>
+                    <Mapping><Layer>Note-Off</Layer><MapVelocityToVolume>false</MapVelocityToVolume></Mapping>
+                  </Sample>
+                </Samples>
+              </SampleGenerator>
+            </RenoiseInstrument>"#;
+        let mut data = Vec::new();
+        {
+            let mut zip = zip::ZipWriter::new(Cursor::new(&mut data));
+            let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+            zip.start_file(XRNI_XML, options).unwrap();
+            zip.write_all(text.as_bytes()).unwrap();
+            let mut wav = Cursor::new(Vec::new());
+            let spec = hound::WavSpec{channels: 1, sample_rate: 44100, bits_per_sample: 16, sample_format: hound::SampleFormat::Int};
+            let mut writer = hound::WavWriter::new(&mut wav, spec).unwrap();
+            for i in 0..8{
+                writer.write_sample(i as i16 * 1000).unwrap();
+            }
+            writer.finalize().unwrap();
+            zip.start_file("SampleData/Sample00 (Low).wav", options).unwrap();
+            zip.write_all(wav.get_ref()).unwrap();
+            zip.finish().unwrap();
+        }
+        let instrument = read_xrni(Cursor::new(data)).unwrap();
+        assert_eq!(instrument.missing_samples(), &["SampleData/Sample01".to_string()]);
+        let (id, low) = instrument.regions_for(40, 100, 0.0).next().unwrap();
+        assert_eq!(id, 0);
+        assert_eq!((low.pitch_keycenter, low.hikey, low.tune, low.pan), (36, 47, 50.0, 50.0));
+        assert!((low.volume + 6.02).abs() < 0.01);
+        assert_eq!((low.loop_mode, low.loop_start, low.loop_end), (Some(SfzLoopMode::LoopContinuous), Some(2), Some(6)));
+        assert_eq!(instrument.sample(low).capacity(), 8);
+        let (regions, _, _) = parse_xrni(text).unwrap();
+        assert_eq!((regions[1].trigger, regions[1].amp_veltrack), (SfzTrigger::Release, 0.0));
+    }
+}
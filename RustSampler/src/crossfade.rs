@@ -68,10 +68,19 @@ impl Crossfade{
         self.state = FaderState::FadingIn;
         self.fade_value = 0.0;
     }
-    /// Trigger to start the fade out
+    /// Trigger to start the fade out. It starts from the current level, so a fade in
+    /// that hasn't finished turns around instead of jumping up to full volume
     pub fn start_fade_out(&mut self){
+        self.fade_value = match self.state{
+            FaderState::NoEffect => 1.0,
+            FaderState::FadingIn if self.in_step == -1.0 => 1.0,
+            FaderState::FadingIn | FaderState::FadingOut => self.fade_value,
+        };
         self.state = FaderState::FadingOut;
-        self.fade_value = 1.0;
+    }
+    /// Returns whether a fade out has reached silence
+    pub fn is_faded_out(&self)->bool{
+        self.state == FaderState::FadingOut && (self.fade_value <= 0.0 || self.out_step == -1.0)
     }
      /// Returns a step size to draw a line of a certain vertical 'distance' (amplitude) 
     /// in a certain amount of time
@@ -92,4 +101,21 @@ fn fclamp(x: f32, min_val: f32, max_val: f32) -> f32 {
     } else {
         x
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests{
+    use super::*;
+    #[test]
+    fn test_fade_out_during_fade_in(){
+        let mut fader = Crossfade::new(1000.0, 0.01, 0.01);
+        fader.start_fade_out();
+        assert_eq!(fader.get_next_sample(), 0.9);
+        fader.start_fade_in();
+        for _ in 0..4{
+            fader.get_next_sample();
+        }
+        fader.start_fade_out();
+        let level = fader.get_next_sample();
+        assert!((level - 0.3).abs() < 1e-6, "{}", level);
+    }
+}
@@ -0,0 +1,522 @@
+use crate::sample_buffer;
+use sample_buffer::{SampleLoop, SampleMarker, SampleMetadata};
+use std::{fmt, fs, io::{self, Cursor}, path::Path};
+
+/// WAVE format tags, which say how the samples are encoded
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// Interleaved audio decoded from a file
+pub struct DecodedAudio{
+    pub samples: Vec<f32>,
+    pub num_channels: usize,
+    pub sample_rate: f32,
+    pub metadata: SampleMetadata,
+}
+
+/// Why an audio file couldn't be decoded
+#[derive(Debug)]
+pub enum DecodeError{
+    /// The file couldn't be read
+    Io(io::Error),
+    /// No decoder handles the file's extension
+    UnknownFormat(String),
+    /// The file is damaged or isn't the format its extension says
+    Malformed(&'static str),
+    /// The file is valid but stores its samples in a way that isn't supported
+    UnsupportedEncoding(String),
+    /// An error from one of the decoding libraries
+    Codec(String),
+}
+
+impl fmt::Display for DecodeError{
+    fn fmt(&self, f: &mut fmt::Formatter)->fmt::Result{
+        match self{
+            DecodeError::Io(e) => write!(f, "{}", e),
+            DecodeError::UnknownFormat(name) => write!(f, "Unsupported audio format: {}", name),
+            DecodeError::Malformed(problem) => write!(f, "Damaged file: {}", problem),
+            DecodeError::UnsupportedEncoding(encoding) => write!(f, "Unsupported encoding: {}", encoding),
+            DecodeError::Codec(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError{}
+
+impl From<io::Error> for DecodeError{
+    fn from(e: io::Error)->Self{
+        DecodeError::Io(e)
+    }
+}
+
+/// Decodes the audio files of one format.
+///
+/// To support another format, implement this and add the decoder to `DECODERS`
+pub trait SampleDecoder: Sync{
+    /// The lowercase file extensions the decoder reads, without the dot
+    fn extensions(&self)->&'static [&'static str];
+    /// Decodes the bytes of a whole file
+    fn decode(&self, data: &[u8])->Result<DecodedAudio, DecodeError>;
+}
+
+/// Every decoder, looked up by file extension
+const DECODERS: &[&dyn SampleDecoder] = &[&WavDecoder, &FlacDecoder, &VorbisDecoder, &Mp3Decoder, &AiffDecoder];
+
+/// Returns the decoder for a file from its extension
+pub fn decoder_for(path: &str)->Option<&'static dyn SampleDecoder>{
+    let extension = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+    DECODERS.iter().copied().find(|decoder| decoder.extensions().contains(&extension.as_str()))
+}
+
+/// Returns whether a file is audio that can be decoded
+pub fn is_supported(path: &str)->bool{
+    decoder_for(path).is_some()
+}
+
+/// Reads and decodes an audio file
+pub fn decode_file(path: &str)->Result<DecodedAudio, DecodeError>{
+    let decoder = decoder_for(path).ok_or_else(|| DecodeError::UnknownFormat(path.to_string()))?;
+    let data = fs::read(path)?;
+    decoder.decode(&data)
+}
+
+/// Decodes an audio file that has already been read, such as one packed inside an
+/// instrument archive. `name` is the file's name, used to pick the decoder
+pub fn decode_bytes(data: &[u8], name: &str)->Result<DecodedAudio, DecodeError>{
+    let decoder = decoder_for(name).ok_or_else(|| DecodeError::UnknownFormat(name.to_string()))?;
+    decoder.decode(data)
+}
+
+/// Reads RIFF WAVE files: unsigned 8 bit, 16, 20, 24 and 32 bit integer and 32 and 64 bit
+/// float samples, in either the plain or the extensible format
+pub struct WavDecoder;
+
+impl SampleDecoder for WavDecoder{
+    fn extensions(&self)->&'static [&'static str]{
+        &["wav", "wave"]
+    }
+    fn decode(&self, data: &[u8])->Result<DecodedAudio, DecodeError>{
+        match data.get(..12){
+            Some(header) if &header[..4] == b"RIFF" && &header[8..12] == b"WAVE" => (),
+            _ => return Err(DecodeError::Malformed("not a RIFF WAVE file")),
+        }
+        let mut format = None;
+        let mut sound = None;
+        let (mut smpl, mut cue, mut adtl, mut acid) = (None, None, None, None);
+        let mut position: usize = 12;
+        while position.saturating_add(8) <= data.len(){
+            let header = &data[position..position + 8];
+            let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            // Streaming writers can leave the size of the data chunk unset, so it's cut to the end of the file
+            let contents = &data[position + 8..(position + 8).saturating_add(size).min(data.len())];
+            match &header[..4]{
+                b"fmt " => format = Some(contents),
+                b"data" => sound = Some(contents),
+                b"smpl" => smpl = Some(contents),
+                b"cue " => cue = Some(contents),
+                b"acid" => acid = Some(contents),
+                b"LIST" if contents.starts_with(b"adtl") => adtl = Some(&contents[4..]),
+                _ => (),
+            }
+            // Chunks are padded to an even length
+            position = position.saturating_add(8 + size + size % 2);
+        }
+        let format = format.ok_or(DecodeError::Malformed("no fmt chunk"))?;
+        let sound = sound.ok_or(DecodeError::Malformed("no data chunk"))?;
+        if format.len() < 16{
+            return Err(DecodeError::Malformed("fmt chunk is too short"));
+        }
+        let read_u16 = |offset: usize| u16::from_le_bytes([format[offset], format[offset + 1]]);
+        let mut format_tag = read_u16(0);
+        let num_channels = read_u16(2) as usize;
+        let sample_rate = u32::from_le_bytes([format[4], format[5], format[6], format[7]]) as f32;
+        let block_align = read_u16(12) as usize;
+        let bits = read_u16(14);
+        if format_tag == WAVE_FORMAT_EXTENSIBLE{
+            // The real format tag is the start of the sub format GUID
+            if format.len() < 26{
+                return Err(DecodeError::Malformed("extensible fmt chunk is too short"));
+            }
+            format_tag = read_u16(24);
+        }
+        if num_channels == 0 || sample_rate <= 0.0 || block_align < num_channels{
+            return Err(DecodeError::Malformed("bad channel count, sample rate or block size"));
+        }
+        // Samples are stored left justified in containers of whole bytes, so a 20 bit sample
+        // in a 3 byte container reads the same as a 24 bit one
+        let container = block_align / num_channels;
+        let convert: fn(&[u8])->f32 = match (format_tag, container){
+            (WAVE_FORMAT_PCM, 1) => |bytes| (bytes[0] as f32 - 128.0) / 128.0,
+            (WAVE_FORMAT_PCM, 2) => |bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+            (WAVE_FORMAT_PCM, 3) => |bytes| i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as f32 / 2147483648.0,
+            (WAVE_FORMAT_PCM, 4) => |bytes| i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32 / 2147483648.0,
+            (WAVE_FORMAT_IEEE_FLOAT, 4) => |bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            (WAVE_FORMAT_IEEE_FLOAT, 8) =>
+                |bytes| f64::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]]) as f32,
+            _ => return Err(DecodeError::UnsupportedEncoding(
+                format!("WAVE format {:#06x} with {} bits per sample", format_tag, bits)
+            )),
+        };
+        let mut samples = Vec::with_capacity(sound.len() / container);
+        for frame in sound.chunks_exact(block_align){
+            samples.extend(frame.chunks_exact(container).take(num_channels).map(convert));
+        }
+        let metadata = wav_metadata(smpl, cue, adtl, acid);
+        Ok(DecodedAudio{samples, num_channels, sample_rate, metadata})
+    }
+}
+
+pub struct FlacDecoder;
+
+impl SampleDecoder for FlacDecoder{
+    fn extensions(&self)->&'static [&'static str]{
+        &["flac"]
+    }
+    fn decode(&self, data: &[u8])->Result<DecodedAudio, DecodeError>{
+        let mut reader = claxon::FlacReader::new(Cursor::new(data)).map_err(|e| DecodeError::Codec(e.to_string()))?;
+        let info = reader.streaminfo();
+        let scale = 1.0 / (1_u64 << (info.bits_per_sample - 1)) as f32;
+        let mut samples = Vec::with_capacity(info.samples.unwrap_or(0) as usize * info.channels as usize);
+        for sample in reader.samples(){
+            samples.push(sample.map_err(|e| DecodeError::Codec(e.to_string()))? as f32 * scale);
+        }
+        Ok(DecodedAudio{
+            samples,
+            num_channels: info.channels as usize,
+            sample_rate: info.sample_rate as f32,
+            metadata: SampleMetadata::default(),
+        })
+    }
+}
+
+pub struct VorbisDecoder;
+
+impl SampleDecoder for VorbisDecoder{
+    fn extensions(&self)->&'static [&'static str]{
+        &["ogg", "oga"]
+    }
+    fn decode(&self, data: &[u8])->Result<DecodedAudio, DecodeError>{
+        let mut reader = lewton::inside_ogg::OggStreamReader::new(Cursor::new(data)).map_err(|e| DecodeError::Codec(e.to_string()))?;
+        let num_channels = reader.ident_hdr.audio_channels as usize;
+        let sample_rate = reader.ident_hdr.audio_sample_rate as f32;
+        let mut samples = Vec::new();
+        while let Some(packet) = reader.read_dec_packet_itl().map_err(|e| DecodeError::Codec(e.to_string()))?{
+            samples.extend(packet.iter().map(|sample| *sample as f32 / i16::MAX as f32));
+        }
+        Ok(DecodedAudio{samples, num_channels, sample_rate, metadata: SampleMetadata::default()})
+    }
+}
+
+pub struct Mp3Decoder;
+
+impl SampleDecoder for Mp3Decoder{
+    fn extensions(&self)->&'static [&'static str]{
+        &["mp3"]
+    }
+    fn decode(&self, data: &[u8])->Result<DecodedAudio, DecodeError>{
+        let mut decoder = minimp3::Decoder::new(Cursor::new(data));
+        let mut audio = DecodedAudio{samples: Vec::new(), num_channels: 0, sample_rate: 0.0, metadata: SampleMetadata::default()};
+        loop{
+            match decoder.next_frame(){
+                Ok(frame) =>{
+                    // Every frame of a file normally has the same layout, so keep the first one's
+                    if audio.num_channels == 0{
+                        audio.num_channels = frame.channels;
+                        audio.sample_rate = frame.sample_rate as f32;
+                    }
+                    if frame.channels == audio.num_channels{
+                        audio.samples.extend(frame.data.iter().map(|sample| *sample as f32 / i16::MAX as f32));
+                    }
+                },
+                Err(minimp3::Error::Eof) => break,
+                Err(minimp3::Error::SkippedData) => continue,
+                Err(e) => return Err(DecodeError::Codec(e.to_string())),
+            }
+        }
+        if audio.num_channels == 0{
+            return Err(DecodeError::Malformed("no MP3 frames"));
+        }
+        Ok(audio)
+    }
+}
+
+/// Reads uncompressed AIFF and AIFC files, big or little endian, integer or float
+pub struct AiffDecoder;
+
+impl SampleDecoder for AiffDecoder{
+    fn extensions(&self)->&'static [&'static str]{
+        &["aif", "aiff", "aifc"]
+    }
+    fn decode(&self, data: &[u8])->Result<DecodedAudio, DecodeError>{
+        let is_aifc = match data.get(..12){
+            Some(header) if &header[..4] == b"FORM" && &header[8..12] == b"AIFF" => false,
+            Some(header) if &header[..4] == b"FORM" && &header[8..12] == b"AIFC" => true,
+            _ => return Err(DecodeError::Malformed("not an AIFF file")),
+        };
+        let mut format = None;
+        let mut sound = None;
+        let mut position: usize = 12;
+        while position.saturating_add(8) <= data.len(){
+            let header = &data[position..position + 8];
+            let size = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            let contents = &data[position + 8..(position + 8).saturating_add(size).min(data.len())];
+            match &header[..4]{
+                b"COMM" if contents.len() >= 18 => format = Some(contents),
+                b"SSND" if contents.len() >= 8 =>{
+                    let offset = u32::from_be_bytes([contents[0], contents[1], contents[2], contents[3]]) as usize;
+                    sound = Some(contents.get(8 + offset..).unwrap_or(&[]));
+                },
+                _ => (),
+            }
+            // Chunks are padded to an even length
+            position = position.saturating_add(8 + size + size % 2);
+        }
+        let format = format.ok_or(DecodeError::Malformed("no COMM chunk"))?;
+        let sound = sound.ok_or(DecodeError::Malformed("no SSND chunk"))?;
+        let num_channels = u16::from_be_bytes([format[0], format[1]]) as usize;
+        let num_frames = u32::from_be_bytes([format[2], format[3], format[4], format[5]]) as usize;
+        let bits = u16::from_be_bytes([format[6], format[7]]) as usize;
+        let sample_rate = extended_to_f64(&format[8..18]) as f32;
+        let compression = if is_aifc {format.get(18..22).ok_or(DecodeError::Malformed("no AIFC compression type"))?} else {b"NONE"};
+        if num_channels == 0 || sample_rate <= 0.0{
+            return Err(DecodeError::Malformed("bad channel count or sample rate"));
+        }
+        let (bytes, little_endian, float) = match compression{
+            b"NONE" | b"twos" => (bits.div_ceil(8), false, false),
+            b"sowt" => (bits.div_ceil(8), true, false),
+            b"fl32" | b"FL32" => (4, false, true),
+            b"fl64" | b"FL64" => (8, false, true),
+            _ => return Err(DecodeError::UnsupportedEncoding(format!("AIFC compression {}", String::from_utf8_lossy(compression)))),
+        };
+        if bytes == 0 || bytes > 8{
+            return Err(DecodeError::UnsupportedEncoding(format!("AIFF with {} bits per sample", bits)));
+        }
+        let num_samples = (num_frames * num_channels).min(sound.len() / bytes);
+        let samples = sound.chunks_exact(bytes).take(num_samples).map(|sample|{
+            let mut raw = [0_u8; 8];
+            // Put the sample in the top bytes of a big endian u64 so the sign ends up in place
+            for (i, byte) in sample.iter().enumerate(){
+                raw[if little_endian {bytes - 1 - i} else {i}] = *byte;
+            }
+            let value = u64::from_be_bytes(raw);
+            if float && bytes == 4{
+                f32::from_bits((value >> 32) as u32)
+            }else if float{
+                f64::from_bits(value) as f32
+            }else{
+                (value as i64 >> (64 - 8 * bytes)) as f32 / (1_u64 << (8 * bytes - 1)) as f32
+            }
+        }).collect();
+        Ok(DecodedAudio{samples, num_channels, sample_rate, metadata: SampleMetadata::default()})
+    }
+}
+
+/// Reads the root note, tuning, loop and markers from a WAV file's chunks.
+///
+/// The `smpl` chunk's note and loop win over the `acid` chunk's root note. Without a
+/// `smpl` loop, the first marker that covers a region of the sample is used as the loop
+fn wav_metadata(smpl: Option<&[u8]>, cue: Option<&[u8]>, adtl: Option<&[u8]>, acid: Option<&[u8]>)->SampleMetadata{
+    let read_u32 = |bytes: &[u8], offset: usize| bytes.get(offset..offset + 4)
+        .map(|value| u32::from_le_bytes([value[0], value[1], value[2], value[3]]));
+    let mut metadata = SampleMetadata::default();
+    if let Some(smpl) = smpl{
+        metadata.root_note = read_u32(smpl, 12).filter(|note| *note <= 127).map(|note| note as u8);
+        // The pitch fraction is a fraction of a semitone, where 0x80000000 is 50 cents
+        metadata.fine_tune = read_u32(smpl, 16).map_or(0.0, |fraction| fraction as f32 / 4294967296.0 * 100.0);
+        let num_loops = read_u32(smpl, 28).unwrap_or(0) as usize;
+        metadata.sustain_loop = smpl.get(36..).unwrap_or(&[])
+            .chunks_exact(24)
+            .take(num_loops)
+            .find_map(|sample_loop|{
+                let kind = read_u32(sample_loop, 4)?;
+                let start = read_u32(sample_loop, 8)? as usize;
+                let end = read_u32(sample_loop, 12)? as usize;
+                // Type 0 loops forwards and 1 back and forth. Backwards loops are played forwards
+                (end > start && kind <= 2).then_some(SampleLoop{start, end: end + 1, bounce: kind == 1})
+            });
+    }
+    if metadata.root_note.is_none(){
+        // The root note only counts if the second flag bit is set
+        if let Some(acid) = acid.filter(|acid| read_u32(acid, 0).is_some_and(|flags| flags & 0x02 != 0)){
+            metadata.root_note = acid.get(4..6).map(|note| u16::from_le_bytes([note[0], note[1]]))
+                .filter(|note| *note <= 127)
+                .map(|note| note as u8);
+        }
+    }
+    if let Some(cue) = cue{
+        let num_cues = read_u32(cue, 0).unwrap_or(0) as usize;
+        let mut markers = cue.get(4..).unwrap_or(&[])
+            .chunks_exact(24)
+            .take(num_cues)
+            .filter_map(|point| Some((read_u32(point, 0)?, read_u32(point, 20)? as usize)))
+            .map(|(id, frame)| (id, SampleMarker{frame, length: 0, label: String::new()}))
+            .collect::<Vec<_>>();
+        // The adtl list holds the labels and region lengths of the cue points, by cue id
+        let mut position: usize = 0;
+        let adtl = adtl.unwrap_or(&[]);
+        while position.saturating_add(12) <= adtl.len(){
+            let id = &adtl[position..position + 4];
+            let size = read_u32(adtl, position + 4).unwrap_or(0) as usize;
+            let contents = &adtl[position + 8..(position + 8).saturating_add(size).min(adtl.len())];
+            let cue_id = read_u32(contents, 0);
+            if let Some((_, marker)) = markers.iter_mut().find(|(marker_id, _)| Some(*marker_id) == cue_id){
+                match id{
+                    b"labl" =>{
+                        let text = &contents[4..];
+                        let text = &text[..text.iter().position(|byte| *byte == 0).unwrap_or(text.len())];
+                        marker.label = String::from_utf8_lossy(text).into_owned();
+                    },
+                    b"ltxt" => marker.length = read_u32(contents, 4).unwrap_or(0) as usize,
+                    _ => (),
+                }
+            }
+            position = position.saturating_add(8 + size + size % 2);
+        }
+        metadata.markers = markers.into_iter().map(|(_, marker)| marker).collect();
+        metadata.markers.sort_by_key(|marker| marker.frame);
+    }
+    if metadata.sustain_loop.is_none(){
+        metadata.sustain_loop = metadata.markers.iter()
+            .find(|marker| marker.length > 0)
+            .map(|marker| SampleLoop{start: marker.frame, end: marker.frame + marker.length, bounce: false});
+    }
+    metadata
+}
+
+/// Reads the 80 bit extended precision float AIFF uses for its sample rate
+fn extended_to_f64(bytes: &[u8])->f64{
+    let exponent = (((bytes[0] & 0x7f) as i32) << 8) | bytes[1] as i32;
+    let mut mantissa = 0_u64;
+    for byte in &bytes[2..10]{
+        mantissa = (mantissa << 8) | *byte as u64;
+    }
+    let value = mantissa as f64 * 2.0_f64.powi(exponent - 16383 - 63);
+    if bytes[0] & 0x80 != 0 {-value} else {value}
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    /// Builds an AIFF or AIFC file at 44.1 kHz
+    fn aiff_file(compression: Option<&[u8]>, channels: u16, bits: u16, sound: &[u8])->Vec<u8>{
+        let frames = sound.len() as u32 / channels as u32 / (bits as u32).div_ceil(8);
+        let mut comm = [channels.to_be_bytes().to_vec(), frames.to_be_bytes().to_vec(), bits.to_be_bytes().to_vec()].concat();
+        comm.extend([0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0]);
+        if let Some(compression) = compression{
+            comm.extend(compression);
+            comm.extend([0, 0]);
+        }
+        let mut body = if compression.is_some() {b"AIFC".to_vec()} else {b"AIFF".to_vec()};
+        for (id, contents) in [(b"COMM", comm), (b"SSND", [vec![0; 8], sound.to_vec()].concat())]{
+            body.extend(id);
+            body.extend((contents.len() as u32).to_be_bytes());
+            body.extend(&contents);
+            if contents.len() % 2 == 1{
+                body.push(0);
+            }
+        }
+        [b"FORM".to_vec(), (body.len() as u32).to_be_bytes().to_vec(), body].concat()
+    }
+    /// Builds a WAVE file at 48 kHz, using the extensible format if `extensible` is true
+    fn wav_file(format_tag: u16, channels: u16, bits: u16, extensible: bool, sound: &[u8])->Vec<u8>{
+        let block_align = channels * bits.div_ceil(8);
+        let mut fmt = [
+            if extensible {WAVE_FORMAT_EXTENSIBLE} else {format_tag}.to_le_bytes().to_vec(),
+            channels.to_le_bytes().to_vec(),
+            48000_u32.to_le_bytes().to_vec(),
+            (48000 * block_align as u32).to_le_bytes().to_vec(),
+            block_align.to_le_bytes().to_vec(),
+            (block_align / channels * 8).to_le_bytes().to_vec(),
+        ].concat();
+        if extensible{
+            fmt.extend(22_u16.to_le_bytes());
+            fmt.extend(bits.to_le_bytes());
+            fmt.extend(0_u32.to_le_bytes());
+            fmt.extend(format_tag.to_le_bytes());
+            fmt.extend([0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71]);
+        }
+        let mut body = b"WAVE".to_vec();
+        for (id, contents) in [(b"fmt ", fmt), (b"LIST", vec![0; 3]), (b"data", sound.to_vec())]{
+            body.extend(id);
+            body.extend((contents.len() as u32).to_le_bytes());
+            body.extend(&contents);
+            if contents.len() % 2 == 1{
+                body.push(0);
+            }
+        }
+        [b"RIFF".to_vec(), (body.len() as u32).to_le_bytes().to_vec(), body].concat()
+    }
+    #[test]
+    fn test_wav(){
+        let audio = decode_bytes(&wav_file(WAVE_FORMAT_PCM, 2, 8, false, &[0, 128, 192, 255]), "a.wav").unwrap();
+        assert_eq!((audio.num_channels, audio.sample_rate), (2, 48000.0));
+        assert_eq!(audio.samples[..3], [-1.0, 0.0, 0.5]);
+        let audio = decode_bytes(&wav_file(WAVE_FORMAT_PCM, 1, 16, false, &[0x00, 0x80, 0x00, 0x40]), "a.wav").unwrap();
+        assert_eq!(audio.samples, vec![-1.0, 0.5]);
+        // 20 bit samples sit in the top of 3 byte containers
+        let audio = decode_bytes(&wav_file(WAVE_FORMAT_PCM, 1, 20, true, &[0x00, 0x00, 0xc0, 0x10, 0x00, 0x40]), "a.wav").unwrap();
+        assert_eq!(audio.samples, vec![-0.5, 0.5 + 16.0 / 8388608.0]);
+        let audio = decode_bytes(&wav_file(WAVE_FORMAT_PCM, 1, 32, false, &i32::MIN.to_le_bytes()), "a.wav").unwrap();
+        assert_eq!(audio.samples, vec![-1.0]);
+        let audio = decode_bytes(&wav_file(WAVE_FORMAT_IEEE_FLOAT, 1, 32, true, &0.25_f32.to_le_bytes()), "a.wav").unwrap();
+        assert_eq!(audio.samples, vec![0.25]);
+        let audio = decode_bytes(&wav_file(WAVE_FORMAT_IEEE_FLOAT, 1, 64, false, &(-0.75_f64).to_le_bytes()), "a.wav").unwrap();
+        assert_eq!(audio.samples, vec![-0.75]);
+        assert!(matches!(
+            decode_bytes(&wav_file(0x0002, 1, 4, false, &[0]), "a.wav"),
+            Err(DecodeError::UnsupportedEncoding(_))
+        ));
+        let truncated = wav_file(WAVE_FORMAT_PCM, 1, 16, false, &[0; 4]);
+        assert!(matches!(decode_bytes(&truncated[..30], "a.wav"), Err(DecodeError::Malformed(_))));
+        assert!(matches!(decode_file("missing.wav"), Err(DecodeError::Io(_))));
+    }
+    /// Adds chunks after the data chunk of a WAVE file
+    fn with_chunks(mut wav: Vec<u8>, chunks: &[(&[u8; 4], Vec<u8>)])->Vec<u8>{
+        for (id, contents) in chunks{
+            wav.extend(*id);
+            wav.extend((contents.len() as u32).to_le_bytes());
+            wav.extend(contents);
+        }
+        let riff_size = (wav.len() as u32 - 8).to_le_bytes();
+        wav[4..8].copy_from_slice(&riff_size);
+        wav
+    }
+    #[test]
+    fn test_wav_metadata(){
+        let words = |values: &[u32]| values.iter().flat_map(|value| value.to_le_bytes()).collect::<Vec<u8>>();
+        let wav = wav_file(WAVE_FORMAT_PCM, 1, 16, false, &[0; 200]);
+        // smpl: unity note 48 a quarter semitone sharp, with a back and forth loop over frames 10 to 89
+        let smpl = words(&[0, 0, 0, 48, 0x4000_0000, 0, 0, 1, 0, 0, 1, 10, 89, 0, 0]);
+        let cue = words(&[2, 1, 0, u32::from_le_bytes(*b"data"), 0, 0, 60, 2, 0, u32::from_le_bytes(*b"data"), 0, 0, 20]);
+        let adtl = [b"adtl".to_vec(), b"labl".to_vec(), words(&[9, 1]), b"Hit\0\0\0".to_vec(),
+            b"ltxt".to_vec(), words(&[20, 2, 30, 0, 0, 0, 0])].concat();
+        let acid = words(&[0x02, 62]);
+        let audio = decode_bytes(&with_chunks(wav.clone(), &[(b"smpl", smpl), (b"acid", acid.clone())]), "a.wav").unwrap();
+        assert_eq!(audio.metadata.root_note, Some(48));
+        assert_eq!(audio.metadata.fine_tune, 25.0);
+        assert_eq!(audio.metadata.sustain_loop, Some(SampleLoop{start: 10, end: 90, bounce: true}));
+        let audio = decode_bytes(&with_chunks(wav, &[(b"cue ", cue), (b"LIST", adtl), (b"acid", acid)]), "a.wav").unwrap();
+        assert_eq!(audio.metadata.root_note, Some(62));
+        assert_eq!(audio.metadata.markers, vec![
+            SampleMarker{frame: 20, length: 30, label: String::new()},
+            SampleMarker{frame: 60, length: 0, label: "Hit".to_string()},
+        ]);
+        assert_eq!(audio.metadata.sustain_loop, Some(SampleLoop{start: 20, end: 50, bounce: false}));
+    }
+    #[test]
+    fn test_aiff(){
+        let audio = decode_bytes(&aiff_file(None, 2, 16, &[0x40, 0x00, 0xc0, 0x00, 0x00, 0x01, 0x7f, 0xff]), "a.AIF").unwrap();
+        assert_eq!((audio.num_channels, audio.sample_rate), (2, 44100.0));
+        assert_eq!(audio.samples[..2], [0.5, -0.5]);
+        assert!((audio.samples[3] - 1.0).abs() < 1e-4);
+        let audio = decode_bytes(&aiff_file(Some(b"sowt"), 1, 24, &[0x00, 0x00, 0xc0, 0x00, 0x00, 0x40]), "a.aifc").unwrap();
+        assert_eq!(audio.samples, vec![-0.5, 0.5]);
+        let audio = decode_bytes(&aiff_file(Some(b"fl32"), 1, 32, &0.25_f32.to_be_bytes()), "a.aifc").unwrap();
+        assert_eq!(audio.samples, vec![0.25]);
+        assert!(decode_bytes(&aiff_file(Some(b"ima4"), 1, 16, &[0, 0]), "a.aifc").is_err());
+        assert!(decode_bytes(b"RIFF", "a.aiff").is_err());
+        assert!(!is_supported("a.sfz"));
+        assert!(is_supported("folder.wav/a.FLAC"));
+    }
+}
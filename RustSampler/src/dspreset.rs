@@ -0,0 +1,208 @@
+use crate::{sampler_engine,sfz,sfz_parser};
+use sampler_engine::create_buffer;
+use sfz::{SfzInstrument, SfzLoopMode, SfzRegion, SfzTrigger};
+use sfz_parser::parse_note;
+use roxmltree::{Document, Node};
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, sync::Arc};
+
+/// Reads a DecentSampler `.dspreset` file and decodes every sample it uses.
+///
+/// Sample paths are resolved relative to the preset's folder. Samples that can't
+/// be found are skipped and listed in the instrument's `missing_samples`
+pub fn from_file(path: &Path)->io::Result<SfzInstrument>{
+    let text = fs::read_to_string(path)?;
+    let preset_dir = path.parent().unwrap_or(Path::new(""));
+    let (regions, sample_paths, mut errors) = parse(&text)?;
+    let mut samples = Vec::new();
+    let mut sample_ids = HashMap::<PathBuf, usize>::new();
+    let mut missing_samples = Vec::new();
+    let mut loaded_regions = Vec::with_capacity(regions.len());
+    for (mut region, sample_path) in regions.into_iter().zip(sample_paths){
+        let file_path = preset_dir.join(sample_path.replace('\\', "/"));
+        region.sample = match sample_ids.get(&file_path){
+            Some(id) => *id,
+            None =>{
+                if !file_path.is_file(){
+                    let missing = file_path.display().to_string();
+                    if !missing_samples.contains(&missing){
+                        missing_samples.push(missing);
+                    }
+                    continue;
+                }
+                match create_buffer(&file_path.to_string_lossy()){
+                    Ok((buffer, _sample_rate)) =>{
+                        samples.push(Arc::new(buffer));
+                        sample_ids.insert(file_path, samples.len() - 1);
+                        samples.len() - 1
+                    },
+                    Err(e) =>{
+                        errors.push(format!("Could not decode {}: {}", file_path.display(), e));
+                        sample_ids.insert(file_path, usize::MAX);
+                        continue;
+                    }
+                }
+            }
+        };
+        if region.sample != usize::MAX{
+            loaded_regions.push(region);
+        }
+    }
+    Ok(SfzInstrument::from_regions(loaded_regions, samples).with_problems(errors, missing_samples))
+}
+
+/// Reads the regions of a `.dspreset` document, with the sample path of each region
+/// and the problems found along the way.
+///
+/// Attributes set on `<groups>` and `<group>` apply to the samples inside them unless
+/// the sample sets them again. Volumes and tunings of each level add together
+pub fn parse(text: &str)->io::Result<(Vec<SfzRegion>, Vec<String>, Vec<String>)>{
+    let document = Document::parse(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let root = document.root_element();
+    if !root.has_tag_name("DecentSampler"){
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a DecentSampler preset"));
+    }
+    let mut regions = Vec::new();
+    let mut sample_paths = Vec::new();
+    let mut errors = Vec::new();
+    let mut group_index = 0;
+    for groups in root.children().filter(|node| node.has_tag_name("groups")){
+        for group in groups.children().filter(|node| node.has_tag_name("group")){
+            let samples = group.children().filter(|node| node.has_tag_name("sample")).collect::<Vec<_>>();
+            // Without seqLength the cycle is as long as the highest seqPosition
+            let seq_length = number(group, "seqLength").map_or_else(
+                || samples.iter().filter_map(|sample| number(*sample, "seqPosition")).fold(1.0, f32::max),
+                |length| length.max(1.0),
+            ) as u32;
+            let seq_mode = inherited(&[group, groups], "seqMode").unwrap_or("always");
+            for sample in samples{
+                let levels = [sample, group, groups];
+                let Some(path) = sample.attribute("path") else{
+                    errors.push("Sample without a path".to_string());
+                    continue;
+                };
+                let mut region = SfzRegion{seq_group: group_index, ..SfzRegion::default()};
+                match inherited(&levels, "rootNote").and_then(parse_note){
+                    Some(note) => region.pitch_keycenter = note,
+                    None =>{
+                        errors.push(format!("Sample {} has no rootNote", path));
+                        continue;
+                    }
+                }
+                region.lokey = inherited(&levels, "loNote").and_then(parse_note).unwrap_or(0);
+                region.hikey = inherited(&levels, "hiNote").and_then(parse_note).unwrap_or(127);
+                region.lovel = inherited(&levels, "loVel").and_then(|value| value.parse().ok()).unwrap_or(0);
+                region.hivel = inherited(&levels, "hiVel").and_then(|value| value.parse().ok()).unwrap_or(127);
+                region.offset = frame(sample, "start").unwrap_or(0);
+                region.end = frame(sample, "end");
+                region.loop_start = frame(sample, "loopStart");
+                region.loop_end = frame(sample, "loopEnd");
+                region.loop_mode = match inherited(&levels, "loopEnabled"){
+                    Some("true") => Some(SfzLoopMode::LoopContinuous),
+                    Some("false") => Some(SfzLoopMode::NoLoop),
+                    _ => None,
+                };
+                region.volume = levels.iter().filter_map(|level| level.attribute("volume").and_then(parse_volume)).sum();
+                region.tune = 100.0 * (inherited_number(&levels, "tuning").unwrap_or(0.0)
+                    + number(group, "groupTuning").unwrap_or(0.0)
+                    + number(groups, "globalTuning").unwrap_or(0.0));
+                region.pan = inherited_number(&levels, "pan").unwrap_or(0.0).clamp(-100.0, 100.0);
+                region.pitch_keytrack = 100.0 * inherited_number(&levels, "pitchKeyTrack").unwrap_or(1.0);
+                region.amp_veltrack = 100.0 * inherited_number(&levels, "ampVelTrack").unwrap_or(1.0);
+                region.ampeg_attack = inherited_number(&levels, "attack").map(|time| time.max(0.0));
+                region.ampeg_decay = inherited_number(&levels, "decay").map(|time| time.max(0.0));
+                region.ampeg_sustain = inherited_number(&levels, "sustain").map(|level| 100.0 * level.clamp(0.0, 1.0));
+                region.ampeg_release = inherited_number(&levels, "release").map(|time| time.max(0.0));
+                if let Some(trigger) = inherited(&levels, "trigger"){
+                    match SfzTrigger::from_name(trigger){
+                        Some(trigger) => region.trigger = trigger,
+                        None => errors.push(format!("Unknown trigger {} on sample {}", trigger, path)),
+                    }
+                }
+                let position = number(sample, "seqPosition").map_or(1, |position| position.max(1.0) as u32);
+                match seq_mode{
+                    "round_robin" =>{
+                        region.seq_length = seq_length;
+                        region.seq_position = position;
+                    },
+                    // Each position gets an equal share of the random range
+                    "random" | "true_random" =>{
+                        region.lorand = (position - 1) as f32 / seq_length as f32;
+                        region.hirand = position as f32 / seq_length as f32;
+                    },
+                    _ => (),
+                }
+                regions.push(region);
+                sample_paths.push(path.to_string());
+            }
+            group_index += 1;
+        }
+    }
+    Ok((regions, sample_paths, errors))
+}
+
+/// Returns the first value of an attribute, looking from the sample out to `<groups>`
+fn inherited<'a>(levels: &[Node<'a, '_>], name: &str)->Option<&'a str>{
+    levels.iter().find_map(|level| level.attribute(name))
+}
+
+fn inherited_number(levels: &[Node], name: &str)->Option<f32>{
+    inherited(levels, name).and_then(|value| value.trim().parse().ok())
+}
+
+fn number(node: Node, name: &str)->Option<f32>{
+    node.attribute(name).and_then(|value| value.trim().parse().ok())
+}
+
+fn frame(node: Node, name: &str)->Option<usize>{
+    node.attribute(name).and_then(|value| value.trim().parse::<f64>().ok()).map(|frame| frame.max(0.0) as usize)
+}
+
+/// Reads a volume in dB, written either as "-3dB" or as a linear gain like "0.5"
+fn parse_volume(value: &str)->Option<f32>{
+    let value = value.trim();
+    let lower = value.to_ascii_lowercase();
+    match lower.strip_suffix("db"){
+        Some(db) => db.trim().parse().ok(),
+        None => value.parse::<f32>().ok().map(|gain| 20.0 * gain.max(1e-6).log10()),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    #[test]
+    fn test_parse(){
+        let text = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <DecentSampler minVersion="1.0.0">
+              <groups attack="0.01" release="0.5" volume="-6dB" seqMode="round_robin">
+                <group volume="0.5" sustain="0.8" loNote="36">
+                  <sample path="Samples/a.wav" rootNote="60" hiNote="61" loVel="1" hiVel="64" seqPosition="1"
+                          loopStart="100" loopEnd="200" loopEnabled="true" tuning="-0.5"/>
+                  <sample path="Samples/b.wav" rootNote="C4" release="1.5" pan="-50" seqPosition="2"
+                          trigger="release"/>
+                  <sample path="Samples/c.wav"/>
+                </group>
+                <group seqMode="random">
+                  <sample path="d.wav" rootNote="40" seqPosition="1"/>
+                  <sample path="e.wav" rootNote="40" seqPosition="2"/>
+                </group>
+              </groups>
+            </DecentSampler>"#;
+        let (regions, paths, errors) = parse(text).unwrap();
+        assert_eq!(errors, vec!["Sample Samples/c.wav has no rootNote".to_string()]);
+        assert_eq!(paths, vec!["Samples/a.wav", "Samples/b.wav", "d.wav", "e.wav"]);
+        let a = &regions[0];
+        assert_eq!((a.lokey, a.hikey, a.lovel, a.hivel, a.pitch_keycenter), (36, 61, 1, 64, 60));
+        assert_eq!((a.loop_start, a.loop_end, a.loop_mode), (Some(100), Some(200), Some(SfzLoopMode::LoopContinuous)));
+        assert!((a.volume + 12.02).abs() < 0.01, "{}", a.volume);
+        assert_eq!(a.tune, -50.0);
+        assert_eq!((a.ampeg_attack, a.ampeg_sustain, a.ampeg_release), (Some(0.01), Some(80.0), Some(0.5)));
+        assert_eq!((a.seq_length, a.seq_position), (2, 1));
+        let b = &regions[1];
+        assert_eq!((b.pitch_keycenter, b.hikey, b.pan, b.ampeg_release), (60, 127, -50.0, Some(1.5)));
+        assert_eq!((b.trigger, b.seq_position), (SfzTrigger::Release, 2));
+        let e = &regions[3];
+        assert_eq!((e.seq_group, e.seq_length, e.lorand, e.hirand), (1, 1, 0.5, 1.0));
+        assert!(parse("<sfz/>").is_err());
+    }
+}
@@ -0,0 +1,54 @@
+/// A two pole lowpass filter for each of the two output channels.
+///
+/// Uses the trapezoidal state variable design, which stays stable while the cutoff moves
+#[derive(Clone)]
+pub struct LowpassFilter{
+    sample_rate: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    k: f32,
+    ic1eq: [f32; 2],
+    ic2eq: [f32; 2],
+}
+
+impl LowpassFilter{
+    pub fn new(sample_rate_: f32)->Self{
+        let mut filter = LowpassFilter{
+            sample_rate: sample_rate_,
+            a1: 0.0,
+            a2: 0.0,
+            a3: 0.0,
+            k: 0.0,
+            ic1eq: [0.0; 2],
+            ic2eq: [0.0; 2],
+        };
+        filter.set_cutoff(sample_rate_ * 0.45, 0.0);
+        filter
+    }
+    /// Sets the cutoff in Hz and the resonance in dB (0 dB is a flat Butterworth response)
+    pub fn set_cutoff(&mut self, cutoff: f32, resonance_db: f32){
+        let cutoff = cutoff.clamp(10.0, self.sample_rate * 0.45);
+        let q = std::f32::consts::FRAC_1_SQRT_2 * 10.0_f32.powf(resonance_db.max(0.0) / 20.0);
+        let g = (std::f32::consts::PI * cutoff / self.sample_rate).tan();
+        self.k = 1.0 / q;
+        self.a1 = 1.0 / (1.0 + g * (g + self.k));
+        self.a2 = g * self.a1;
+        self.a3 = g * self.a2;
+    }
+    /// Clears the filter's memory so a new note doesn't ring with the last one
+    pub fn reset(&mut self){
+        self.ic1eq = [0.0; 2];
+        self.ic2eq = [0.0; 2];
+    }
+    /// Filters one sample of a channel (0 or 1)
+    pub fn process(&mut self, channel: usize, input: f32)->f32{
+        let channel = channel.min(1);
+        let v3 = input - self.ic2eq[channel];
+        let v1 = self.a1 * self.ic1eq[channel] + self.a2 * v3;
+        let v2 = self.ic2eq[channel] + self.a2 * self.ic1eq[channel] + self.a3 * v3;
+        self.ic1eq[channel] = 2.0 * v1 - self.ic1eq[channel];
+        self.ic2eq[channel] = 2.0 * v2 - self.ic2eq[channel];
+        v2
+    }
+}
@@ -0,0 +1,117 @@
+use crate::resampler;
+use resampler::Kernel;
+use nih_plug::params::enums::Enum;
+use std::sync::OnceLock;
+
+/// Zero crossings on each side of the sinc interpolator's kernel
+const SINC_ZERO_CROSSINGS: usize = 8;
+const SINC_BETA: f64 = 7.0;
+/// Cutoff of the sinc as a fraction of Nyquist, leaving room for the kernel to roll off
+const SINC_BANDWIDTH: f32 = 0.9;
+/// The sinc widens as the pitch goes up to keep out aliasing. Beyond this many samples
+/// per step it stops widening so high notes don't cost too many taps
+const MAX_SINC_STEP: f32 = 4.0;
+
+static SINC_KERNEL: OnceLock<Kernel> = OnceLock::new();
+
+/// How the voices read between the frames of a sample
+#[derive(Clone, Copy, PartialEq, Enum, Debug)]
+pub enum Interpolation{
+    /// Straight line between two frames. Cheapest, but dulls and aliases when transposing
+    #[name = "Linear"]
+    Linear,
+    /// Catmull-Rom spline through four frames
+    #[name = "Cubic Hermite"]
+    Hermite,
+    #[name = "4-point Lagrange"]
+    Lagrange4,
+    #[name = "6-point Lagrange"]
+    Lagrange6,
+    /// Windowed sinc that lowers its cutoff as the pitch goes up
+    #[name = "Sinc"]
+    Sinc,
+}
+
+impl Interpolation{
+    pub fn iter()->impl Iterator<Item = Self>{
+        [Interpolation::Linear, Interpolation::Hermite, Interpolation::Lagrange4, Interpolation::Lagrange6, Interpolation::Sinc].iter().copied()
+    }
+}
+
+/// Builds the sinc table ahead of time so the audio thread never has to build it
+pub fn prepare_tables(){
+    sinc_kernel();
+}
+
+/// Returns the value at a non-integer `position`, reading frames with `read`.
+///
+/// `step` is how many frames the position moves each output sample, which the sinc
+/// uses to filter out what a transposition up would alias
+pub fn interpolate(read: impl Fn(isize)->f32, position: f32, mode: Interpolation, step: f32)->f32{
+    let floor = position.floor();
+    let index = floor as isize;
+    let frac = position - floor;
+    match mode{
+        Interpolation::Linear => read(index) * (1.0 - frac) + read(index + 1) * frac,
+        Interpolation::Hermite =>{
+            let (before, y0, y1, after) = (read(index - 1), read(index), read(index + 1), read(index + 2));
+            let c1 = 0.5 * (y1 - before);
+            let c2 = before - 2.5 * y0 + 2.0 * y1 - 0.5 * after;
+            let c3 = 0.5 * (after - before) + 1.5 * (y0 - y1);
+            ((c3 * frac + c2) * frac + c1) * frac + y0
+        },
+        Interpolation::Lagrange4 => lagrange::<4>(read, index, frac),
+        Interpolation::Lagrange6 => lagrange::<6>(read, index, frac),
+        Interpolation::Sinc =>{
+            let kernel = sinc_kernel();
+            let cutoff = SINC_BANDWIDTH / step.abs().clamp(1.0, MAX_SINC_STEP);
+            let reach = (SINC_ZERO_CROSSINGS as f32 / cutoff).ceil() as isize;
+            (1 - reach..=reach)
+                .map(|offset| read(index + offset) * cutoff * kernel.at(((frac - offset as f32) * cutoff) as f64) as f32)
+                .sum()
+        },
+    }
+}
+
+/// Fits a polynomial through the `N` frames around `index`, which is the frame before
+/// the position, and returns its value `frac` frames after `index`
+fn lagrange<const N: usize>(read: impl Fn(isize)->f32, index: isize, frac: f32)->f32{
+    let first = 1 - (N / 2) as isize;
+    let mut sum = 0.0;
+    for node in first..first + N as isize{
+        let mut weight = 1.0;
+        for other in first..first + N as isize{
+            if other != node{
+                weight *= (frac - other as f32) / (node - other) as f32;
+            }
+        }
+        sum += weight * read(index + node);
+    }
+    sum
+}
+
+fn sinc_kernel()->&'static Kernel{
+    SINC_KERNEL.get_or_init(|| Kernel::new(SINC_ZERO_CROSSINGS, SINC_BETA))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    #[test]
+    fn test_interpolate(){
+        let frames = [0.0, 1.0, 4.0, 9.0, 16.0, 25.0, 36.0, 49.0, 64.0];
+        let read = |index: isize| frames.get(index as usize).copied().unwrap_or(0.0);
+        // Every mode passes through the frames themselves
+        for mode in Interpolation::iter().filter(|mode| *mode != Interpolation::Sinc){
+            assert_eq!(interpolate(read, 4.0, mode, 1.0), 16.0, "{:?}", mode);
+        }
+        // x² is a polynomial the cubic and higher modes reproduce exactly
+        assert_eq!(interpolate(read, 4.5, Interpolation::Linear, 1.0), 20.5);
+        for mode in [Interpolation::Hermite, Interpolation::Lagrange4, Interpolation::Lagrange6]{
+            assert!((interpolate(read, 4.5, mode, 1.0) - 20.25).abs() < 1e-4, "{:?}", mode);
+        }
+        // A slow sine well inside the sinc's passband
+        let sine = |index: isize| (index as f32 * 0.1).sin();
+        assert!((interpolate(sine, 50.3, Interpolation::Sinc, 1.0) - 5.03f32.sin()).abs() < 1e-3);
+    }
+}
@@ -5,8 +5,23 @@ mod ring_buffer;
 mod sampler_voice;
 mod sampler_engine;
 mod crossfade;
-use sampler_engine::{SamplerEngine,SamplerMode};
+mod sample_buffer;
+mod loader;
+mod sfz;
+mod sfz_parser;
+mod sfz_preprocessor;
+mod filter;
+mod sf2;
+mod dspreset;
+mod archive;
+mod decoder;
+mod resampler;
+mod interpolation;
+use loader::{LoadTask, SampleLoader};
+use sampler_engine::{EngineEvent,EngineParams,SamplerEngine,SamplerMode};
 use sampler_voice::SustainModes;
+use resampler::ResampleQuality;
+use interpolation::Interpolation;
 use egui::{ColorImage, ImageData, TextureHandle, TextureOptions, Context as EguiContext, Color32};
 use image::{DynamicImage, GenericImageView, ImageFormat, RgbaImage};
 use nih_plug::prelude::*;
@@ -14,15 +29,34 @@ use egui::epaint::{PathShape, Pos2, Stroke, Rect};
 use std::{fs, io::Seek};
 use egui_file::FileDialog;
 use homedir::get_my_home;
-use std::{path::PathBuf, sync::{Arc, Mutex}};
+use std::{path::PathBuf, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering}}};
 use std::env::current_dir;
 
 
+/// The largest number of samples rendered between parameter updates
+const MAX_BLOCK_SIZE: usize = 64;
+/// The number of note events per block that can be queued without allocating
+const MAX_EVENTS: usize = 1024;
+/// Stored in `RustSampler::keyswitch` when no key switch is active
+const NO_KEYSWITCH: u8 = u8::MAX;
+
 struct RustSampler {
     params: Arc<RustSamplerParams>,
     engine: Option<SamplerEngine>,  
     file_dialog: Arc<Mutex<FileDialog>>,
     file_path: Arc<FilePaths>,
+    /// Decodes files on the background thread and hands them to the audio thread
+    loader: Arc<SampleLoader>,
+    /// The note events of the block being rendered, allocated up front
+    events: Vec<EngineEvent>,
+    /// The last sfz key switch pressed, shown in the editor. `NO_KEYSWITCH` if there is none
+    keyswitch: Arc<AtomicU8>,
+    /// The SoundFont preset chosen in the editor, as an index into the loader's preset names
+    sf2_preset: Arc<AtomicUsize>,
+    /// Whether the warp sample's own loop is playing instead of the sustain params, shown in the editor
+    file_loop: Arc<AtomicBool>,
+    /// The resampling quality the current file was last loaded with
+    loaded_quality: ResampleQuality,
 }
 
 #[derive(Params)]
@@ -59,6 +93,10 @@ struct RustSamplerParams {
     pub sus_mode: EnumParam<SustainModes>,
     #[id = "fade_time"]
     pub fade_time: FloatParam,
+    #[id = "resample_quality"]
+    pub resample_quality: EnumParam<ResampleQuality>,
+    #[id = "interpolation"]
+    pub interpolation: EnumParam<Interpolation>,
 }
 
 impl Default for RustSampler {
@@ -68,6 +106,12 @@ impl Default for RustSampler {
             file_dialog: Arc::new(Mutex::new(FileDialog::open_file(get_my_home().unwrap()))),
             engine: None,
             file_path: Arc::new(FilePaths::new()),
+            loader: Arc::new(SampleLoader::new()),
+            events: Vec::with_capacity(MAX_EVENTS),
+            keyswitch: Arc::new(AtomicU8::new(NO_KEYSWITCH)),
+            sf2_preset: Arc::new(AtomicUsize::new(0)),
+            file_loop: Arc::new(AtomicBool::new(false)),
+            loaded_quality: ResampleQuality::Good,
             }
     }
 }
@@ -168,7 +212,34 @@ impl Default for RustSamplerParams {
                 .with_smoother(SmoothingStyle::Linear(20.0))
                 .with_unit("ms")
                 .with_step_size(1.0),
+            resample_quality: EnumParam::new(
+                "Resampling",
+                ResampleQuality::Good,
+            ),
+            interpolation: EnumParam::new(
+                "Interpolation",
+                Interpolation::Hermite,
+            ),
+        }
+    }
+}
 
+impl RustSamplerParams {
+    /// Collects the engine's parameters, advancing the smoothers by a block of `block_len` samples
+    fn engine_params(&self, block_len: u32) -> EngineParams {
+        EngineParams {
+            attack: self.attack.smoothed.next_step(block_len)*0.001,
+            decay: self.decay.smoothed.next_step(block_len)*0.001,
+            sustain: self.sustain.smoothed.next_step(block_len),
+            release: self.release.smoothed.next_step(block_len)*0.001,
+            start_point: self.start_point.smoothed.next_step(block_len),
+            end_point: self.end_point.smoothed.next_step(block_len),
+            sus_start: self.sus_start.smoothed.next_step(block_len),
+            sus_end: self.sus_end.smoothed.next_step(block_len),
+            sus_mode: self.sus_mode.value(),
+            fade_time: self.fade_time.value()*0.001,
+            num_voices: self.num_voices.value() as u8,
+            interpolation: self.interpolation.value(),
         }
     }
 }
@@ -197,7 +268,7 @@ impl Plugin for RustSampler {
     }];
 
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
 
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
@@ -206,20 +277,28 @@ impl Plugin for RustSampler {
     // messages here. The type implements the `SysExMessage` trait, which allows conversion to and
     // from plain byte buffers.
     type SysExMessage = ();
-    // More advanced plugins can use this to run expensive background tasks. See the field's
-    // documentation for more information. `()` means that the plugin does not have any background
-    // tasks.
-    type BackgroundTask = ();
+    // Samples are decoded on the background thread so loading a file never blocks the audio
+    // thread. The finished instrument is picked up at the start of the next process call.
+    type BackgroundTask = LoadTask;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        let loader = self.loader.clone();
+        Box::new(move |task| loader.run(task))
+    }
+
     
-    fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+    fn editor(&mut self, async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
         let params = self.params.clone();
         let file_dialog = self.file_dialog.clone();
         let mut file_path = self.file_path.clone();
+        let loader = self.loader.clone();
+        let keyswitch = self.keyswitch.clone();
+        let sf2_preset = self.sf2_preset.clone();
+        let file_loop = self.file_loop.clone();
         create_egui_editor(
             self.params.editor_state.clone(),
             (),
@@ -301,6 +380,27 @@ impl Plugin for RustSampler {
                         start_text = path.clone();
                     }
                     ui.label(start_text);
+                    for message in loader.messages() {
+                        ui.colored_label(Color32::from_rgb(220, 120, 60), message);
+                    }
+                    let active_keyswitch = keyswitch.load(Ordering::Relaxed);
+                    if active_keyswitch != NO_KEYSWITCH {
+                        let articulation = loader.keyswitch_label(active_keyswitch)
+                            .unwrap_or_else(|| format!("Key {}", active_keyswitch));
+                        ui.label(format!("Articulation: {}", articulation));
+                    }
+                    let preset_names = loader.preset_names();
+                    if !preset_names.is_empty() {
+                        let mut selected = sf2_preset.load(Ordering::Relaxed).min(preset_names.len() - 1);
+                        egui::ComboBox::from_label("Preset")
+                            .selected_text(preset_names[selected].as_str())
+                            .show_ui(ui, |ui| {
+                                for (i, name) in preset_names.iter().enumerate() {
+                                    ui.selectable_value(&mut selected, i, name.as_str());
+                                }
+                            });
+                        sf2_preset.store(selected, Ordering::Relaxed);
+                    }
                     if (ui.button("Open")).clicked() {
                         file_dialog.lock().unwrap().open();
                     }
@@ -371,7 +471,31 @@ impl Plugin for RustSampler {
                             setter.set_parameter(&params.sus_mode, selected_m)
                         }
                     });
+                    if file_loop.load(Ordering::Relaxed) {
+                        ui.label("Playing the loop from the sample file until the sustain settings are changed");
+                    }
                     ui.end_row();
+                    ui.label("Resampling");
+                    ui.horizontal(|ui| {
+                        let mut selected_q = params.resample_quality.value();
+                        for quality in ResampleQuality::iter() {
+                            ui.selectable_value(&mut selected_q, quality, ResampleQuality::variants()[quality.to_index()]);
+                        }
+                        if selected_q != params.resample_quality.value() {
+                            // `process` loads the file again with the new quality
+                            setter.set_parameter(&params.resample_quality, selected_q);
+                        }
+                    });
+                    ui.label("Interpolation");
+                    ui.horizontal(|ui| {
+                        let mut selected_i = params.interpolation.value();
+                        for mode in Interpolation::iter() {
+                            ui.selectable_value(&mut selected_i, mode, Interpolation::variants()[mode.to_index()]);
+                        }
+                        if selected_i != params.interpolation.value() {
+                            setter.set_parameter(&params.interpolation, selected_i)
+                        }
+                    });
                     // Handle the fade_time slider
                     let mut fade_time = params.fade_time.value();
                     let fade_time_slider = egui::Slider::new(&mut fade_time, 0.0..=500.0).text("Crossfade Time (ms)");
@@ -408,8 +532,14 @@ impl Plugin for RustSampler {
                 }); 
                 if file_dialog.lock().unwrap().show(egui_ctx).selected() {
                     if let Some(file) = file_dialog.lock().unwrap().path() {
-                        file_path.set_path(String::from(file.to_str().unwrap()));
-                        dbg!(Some(file.to_path_buf()));
+                        match file.to_str() {
+                            Some(path) => {
+                                file_path.set_path(String::from(path));
+                                sf2_preset.store(0, Ordering::Relaxed);
+                                async_executor.execute_background(LoadTask::Load(String::from(path), params.resample_quality.value()));
+                            }
+                            None => loader.report(format!("Could not read {}: the path is not valid UTF-8", file.to_string_lossy())),
+                        }
                     }
                 } 
             },
@@ -420,31 +550,31 @@ impl Plugin for RustSampler {
     fn initialize(
         &mut self,
         _audio_io_layout: &AudioIOLayout,
-        _buffer_config: &BufferConfig,
-        _context: &mut impl InitContext<Self>,
+        buffer_config: &BufferConfig,
+        context: &mut impl InitContext<Self>,
     ) -> bool {
         // Resize buffers and perform other potentially expensive initialization operations here.
         // The `reset()` function is always called right after this function. You can remove this
         // function if you do not need it.
-        let engine_ = SamplerEngine::new(_buffer_config.sample_rate, 2);
+        let engine_ = SamplerEngine::new(buffer_config.sample_rate, 2);
         self.engine = Some(engine_);
 
         self.engine.as_mut().unwrap().set_mode(SamplerMode::Warp);
         self.engine.as_mut().unwrap().set_warp_base(60);
+        // The new engine starts empty, so load the current file again
+        self.loader.set_sample_rate(buffer_config.sample_rate);
+        self.loaded_quality = self.params.resample_quality.value();
+        if let Some(path) = self.file_path.get_path(){
+            context.execute(LoadTask::Load(path, self.loaded_quality));
+        }
         true
     }
 
     fn reset(&mut self) {
         // Reset buffers and envelopes here. This can be called from the audio thread and may not
         // allocate. You can remove this function if you do not need it.
-        if let Some(path) = self.file_path.get_path(){
-            if path.ends_with(".wav"){
-                self.engine.as_mut().unwrap().set_mode(SamplerMode::Warp);
-                self.engine.as_mut().unwrap().load_file_from_path(&path);
-            }else if path.ends_with(".sfz"){
-                self.engine.as_mut().unwrap().load_sfz(path.as_str());
-                self.engine.as_mut().unwrap().set_mode(SamplerMode::Sfz);
-            }
+        if let Some(engine) = self.engine.as_mut(){
+            engine.stop_all_voices();
         }
     }
 
@@ -455,72 +585,101 @@ impl Plugin for RustSampler {
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
         let mut next_event = context.next_event();
-        
-        if self.file_path.is_new_file_loaded(){
-            self.file_path.clear_new_file_flag();
-            self.reset();
+
+        // Samples are converted while loading, so a new quality loads the file again. Checked
+        // here so automation and the host's own editor reload it as well as the gui
+        let quality = self.params.resample_quality.value();
+        if quality != self.loaded_quality {
+            self.loaded_quality = quality;
+            context.execute_background(LoadTask::Reload(quality));
         }
-        for channel_samples in buffer.iter_samples() {
-            // Smoothing is optionally built into the parameters themselves
-            // TODO: Find out why no audio... not getting midi messages
-            while let Some(event) = next_event{
-                match event{
-                    NoteEvent::NoteOn { note, velocity, .. } => {
-                        self.engine.as_mut().unwrap().note_on(note, velocity);
-                    }
-                    NoteEvent::NoteOff { note, .. } => {
-                        self.engine.as_mut().unwrap().note_off(note);
-                    }
-                    _ => (),
+
+        if let Some(instrument) = self.loader.try_recv(){
+            self.engine.as_mut().unwrap().swap_instrument(instrument);
+        }
+        self.engine.as_mut().unwrap().set_sf2_preset(self.sf2_preset.load(Ordering::Relaxed));
+        let swapping = self.engine.as_mut().unwrap().is_swapping();
+        let output = buffer.as_slice();
+        let num_samples = output[0].len();
+        let mut block_start = 0;
+        while block_start < num_samples {
+            // Parameters are handed to the engine once per block instead of once per sample
+            let block_end = (block_start + MAX_BLOCK_SIZE).min(num_samples);
+            let block_len = block_end - block_start;
+            // Collect this block's notes with their offsets so they land on the exact sample.
+            // The last block also takes any events the host placed past the end of the buffer
+            self.events.clear();
+            while let Some(event) = next_event {
+                if event.timing() as usize >= block_end && block_end < num_samples {
+                    break;
+                }
+                // Pushing past the capacity would allocate, so the rest waits for the next block
+                if self.events.len() == MAX_EVENTS {
+                    break;
+                }
+                let timing = (event.timing() as usize).saturating_sub(block_start) as u32;
+                if let Some(engine_event) = engine_event(&event, timing) {
+                    self.events.push(engine_event);
                 }
                 next_event = context.next_event();
             }
+            let engine = self.engine.as_mut().unwrap();
+            engine.set_params(self.params.engine_params(block_len as u32));
 
-            for sample in channel_samples {
+            let (left, right) = output.split_at_mut(1);
+            let mut block = [&mut left[0][block_start..block_end], &mut right[0][block_start..block_end]];
+            engine.render(&mut block, &self.events);
+            for i in 0..block_len {
                 let gain = self.params.gain.smoothed.next();
-                let attack = self.params.attack.smoothed.next()*0.001;
-                let decay = self.params.decay.smoothed.next()*0.001;
-                let sustain = self.params.sustain.smoothed.next();
-                let release = self.params.release.smoothed.next()*0.001;
-                let num_voices = self.params.num_voices.value();
-                let start = self.params.start_point.smoothed.next();
-                let end = self.params.end_point.smoothed.next();
-                let sus_start = self.params.sus_start.smoothed.next();
-                let sus_end = self.params.sus_end.smoothed.next();
-                let sus_mode = self.params.sus_mode.value();
-                let fade_time = self.params.fade_time.value()*0.001;
-                self.engine.as_mut().unwrap().set_num_voices(num_voices as u8);
-                self.engine.as_mut().unwrap().set_adsr_warp(attack, decay, sustain, release);
-                self.engine.as_mut().unwrap().set_points_warp(start, end);
-                self.engine.as_mut().unwrap().set_sus_looping_warp(sus_mode);
-                self.engine.as_mut().unwrap().set_sus_points_warp(sus_start, sus_end);
-                self.engine.as_mut().unwrap().set_fade_time_warp(fade_time);
-                *sample = self.engine.as_mut().unwrap().process();
-                *sample *= gain;
+                for channel in block.iter_mut() {
+                    channel[i] *= gain;
+                }
             }
+            block_start = block_end;
+        }
+        // Events that didn't fit in the last block are applied at its end so none are lost
+        let engine = self.engine.as_mut().unwrap();
+        while let Some(event) = next_event {
+            if let Some(engine_event) = engine_event(&event, 0) {
+                engine.handle_event(engine_event);
+            }
+            next_event = context.next_event();
+        }
+        self.keyswitch.store(engine.last_keyswitch().unwrap_or(NO_KEYSWITCH), Ordering::Relaxed);
+        self.file_loop.store(engine.uses_file_loop(), Ordering::Relaxed);
+        // The replaced instrument is only let go of here, the loader frees it in the background
+        if swapping && !engine.is_swapping() {
+            context.execute_background(LoadTask::ReleaseUnused);
         }
 
         ProcessStatus::Normal
     }
 }
 
+/// Converts the host's note events to the ones the engine plays, at `timing` samples into a block
+fn engine_event(event: &NoteEvent<()>, timing: u32) -> Option<EngineEvent> {
+    match *event {
+        NoteEvent::NoteOn { note, velocity, .. } => Some(EngineEvent::NoteOn { timing, note, velocity }),
+        NoteEvent::NoteOff { note, .. } => Some(EngineEvent::NoteOff { timing, note }),
+        NoteEvent::MidiCC { cc, value, .. } => Some(EngineEvent::MidiCC { timing, cc, value }),
+        _ => None,
+    }
+}
+
 pub struct FilePaths {
     path: Mutex<Option<String>>,
-    new_file_loaded: Mutex<bool>,
 }
 
 impl FilePaths {
     pub fn new() -> Self {
         Self {
             path: Mutex::new(None),
-            new_file_loaded: Mutex::new(false),
         }
     }
 
     pub fn set_path(&self, path: String) {
         let mut guard = self.path.lock().unwrap();
         *guard = Some(path);
-        *self.new_file_loaded.lock().unwrap() = true;
     }
 
     pub fn get_path(&self) -> Option<String> {
@@ -531,14 +690,6 @@ impl FilePaths {
         let mut guard = self.path.lock().unwrap();
         *guard = None;
     }
-
-    pub fn is_new_file_loaded(&self) -> bool {
-        *self.new_file_loaded.lock().unwrap()
-    }
-
-    pub fn clear_new_file_flag(&self) {
-        *self.new_file_loaded.lock().unwrap() = false;
-    }
 }
 
 impl ClapPlugin for RustSampler {
@@ -0,0 +1,223 @@
+use crate::{sample_buffer,sampler_engine,sfz,sf2,dspreset,archive,decoder,resampler};
+use sample_buffer::SampleBuffer;
+use sampler_engine::create_buffer;
+use sfz::SfzInstrument;
+use sf2::Sf2Bank;
+use resampler::{ResampleQuality, resample_shared};
+use crossbeam::queue::ArrayQueue;
+use std::{fs, path::Path, sync::{Arc, Mutex, atomic::{AtomicU32, Ordering}}};
+
+/// Tasks run on nih-plug's background thread
+pub enum LoadTask{
+    /// Decode the audio or instrument file at the given path, converting its samples
+    /// to the session's sample rate with the given quality
+    Load(String, ResampleQuality),
+    /// Decode the last file again with a new quality. Lets the audio thread ask for a
+    /// reload without handling the path
+    Reload(ResampleQuality),
+    /// Free the instruments the audio thread has stopped using
+    ReleaseUnused,
+}
+
+/// An instrument that has been fully decoded and is ready to be swapped into the engine
+#[derive(Clone)]
+pub enum LoadedInstrument{
+    Warp(Arc<SampleBuffer>),
+    Sfz(Arc<SfzInstrument>),
+    Sf2(Arc<Sf2Bank>),
+}
+
+impl LoadedInstrument{
+    /// Returns whether the loader holds the only reference to the instrument's data
+    fn is_unused(&self)->bool{
+        match self{
+            LoadedInstrument::Warp(buffer) => Arc::strong_count(buffer) == 1,
+            LoadedInstrument::Sfz(instrument) => Arc::strong_count(instrument) == 1 && instrument.is_unused(),
+            LoadedInstrument::Sf2(bank) => Arc::strong_count(bank) == 1 && bank.is_unused(),
+        }
+    }
+}
+
+/// Loads instruments on the background thread and hands them to the audio thread.
+///
+/// The audio thread only ever pops from a lock-free queue. The loader keeps a reference
+/// to everything it has sent, so when the engine replaces an instrument the memory is
+/// freed here instead of on the audio thread
+pub struct SampleLoader{
+    loaded: ArrayQueue<LoadedInstrument>,
+    in_use: Mutex<Vec<LoadedInstrument>>,
+    messages: Mutex<Vec<String>>,
+    keyswitch_labels: Mutex<Vec<(u8, String)>>,
+    preset_names: Mutex<Vec<String>>,
+    /// The path of the last file that was loaded, for reloads
+    last_path: Mutex<Option<String>>,
+    /// The session's sample rate as `f32` bits, zero until the plugin is initialized
+    sample_rate: AtomicU32,
+}
+
+impl SampleLoader{
+    pub fn new()->Self{
+        SampleLoader{
+            loaded: ArrayQueue::new(1),
+            in_use: Mutex::new(Vec::new()),
+            messages: Mutex::new(Vec::new()),
+            keyswitch_labels: Mutex::new(Vec::new()),
+            preset_names: Mutex::new(Vec::new()),
+            last_path: Mutex::new(None),
+            sample_rate: AtomicU32::new(0),
+        }
+    }
+    /// Runs a task. Should only be called from the background thread
+    pub fn run(&self, task: LoadTask){
+        match task{
+            LoadTask::Load(path, quality) =>{
+                *self.last_path.lock().unwrap() = Some(path.clone());
+                let mut messages = Vec::new();
+                let sample_rate = f32::from_bits(self.sample_rate.load(Ordering::Relaxed));
+                if let Some(instrument) = load_instrument(&path, sample_rate, quality, &mut messages){
+                    let labels = match &instrument{
+                        LoadedInstrument::Sfz(sfz) => sfz.keyswitch_labels().to_vec(),
+                        LoadedInstrument::Warp(_) | LoadedInstrument::Sf2(_) => Vec::new(),
+                    };
+                    let preset_names = match &instrument{
+                        LoadedInstrument::Sf2(bank) => bank.presets()
+                            .iter()
+                            .map(|preset| format!("{:03}:{:03} {}", preset.bank, preset.program, preset.name))
+                            .collect(),
+                        LoadedInstrument::Warp(_) | LoadedInstrument::Sfz(_) => Vec::new(),
+                    };
+                    *self.keyswitch_labels.lock().unwrap() = labels;
+                    *self.preset_names.lock().unwrap() = preset_names;
+                    self.send(instrument);
+                }
+                *self.messages.lock().unwrap() = messages;
+            },
+            LoadTask::Reload(quality) =>{
+                let path = self.last_path.lock().unwrap().clone();
+                if let Some(path) = path{
+                    self.run(LoadTask::Load(path, quality));
+                }
+            },
+            LoadTask::ReleaseUnused => self.release_unused(),
+        }
+    }
+    /// Sets the rate samples are converted to when they're loaded
+    pub fn set_sample_rate(&self, sample_rate: f32){
+        self.sample_rate.store(sample_rate.to_bits(), Ordering::Relaxed);
+    }
+    /// Returns the most recently loaded instrument if the audio thread hasn't taken it yet.
+    ///
+    /// Never blocks or allocates, so it is safe to call from the audio thread
+    pub fn try_recv(&self)->Option<LoadedInstrument>{
+        self.loaded.pop()
+    }
+    /// Returns the problems found while loading the last file, for display in the gui
+    pub fn messages(&self)->Vec<String>{
+        self.messages.lock().unwrap().clone()
+    }
+    /// Replaces the messages with a problem found before a file could be loaded
+    pub fn report(&self, message: String){
+        *self.messages.lock().unwrap() = vec![message];
+    }
+    /// Returns the name of the articulation a key switch selects in the last loaded instrument
+    pub fn keyswitch_label(&self, key: u8)->Option<String>{
+        let labels = self.keyswitch_labels.lock().unwrap();
+        labels.iter().find(|(labelled, _)| *labelled == key).map(|(_, label)| label.clone())
+    }
+    /// Returns the names of the presets of the last loaded SoundFont, empty for other files
+    pub fn preset_names(&self)->Vec<String>{
+        self.preset_names.lock().unwrap().clone()
+    }
+    /// Queues an instrument for the audio thread, replacing one that hasn't been picked up
+    fn send(&self, instrument: LoadedInstrument){
+        self.in_use.lock().unwrap().push(instrument.clone());
+        // An instrument the audio thread never picked up is dropped here
+        drop(self.loaded.force_push(instrument));
+        self.release_unused();
+    }
+    /// Frees the instruments that neither the audio thread nor the queue holds anymore
+    pub fn release_unused(&self){
+        self.in_use.lock().unwrap().retain(|instrument| !instrument.is_unused());
+    }
+}
+
+/// Decodes an audio file as a warp sample, or an sfz, sf2, dspreset, multisample or xrni
+/// instrument. Other file types are ignored
+/// 
+/// Samples are converted to `sample_rate` unless `quality` is `Off`. Anything that goes
+/// wrong is added to `messages`
+fn load_instrument(path: &str, sample_rate: f32, quality: ResampleQuality, messages: &mut Vec<String>)->Option<LoadedInstrument>{
+    if decoder::is_supported(path){
+        match create_buffer(path){
+            Ok((buffer, _sample_rate)) => Some(LoadedInstrument::Warp(resample_shared(&Arc::new(buffer), sample_rate, quality))),
+            Err(e) =>{
+                messages.push(format!("Could not decode {}: {}", path, e));
+                None
+            }
+        }
+    }else if [".sfz", ".dspreset", ".multisample", ".xrni"].iter().any(|extension| path.ends_with(extension)){
+        // Other multi-zone formats are read into the same regions as sfz files
+        let instrument = if path.ends_with(".sfz"){
+            SfzInstrument::from_file(Path::new(path))
+        }else if path.ends_with(".dspreset"){
+            dspreset::from_file(Path::new(path))
+        }else if path.ends_with(".multisample"){
+            fs::File::open(path).and_then(archive::read_multisample)
+        }else{
+            fs::File::open(path).and_then(archive::read_xrni)
+        };
+        match instrument{
+            Ok(mut instrument) =>{
+                instrument.resample(sample_rate, quality);
+                messages.extend(instrument.errors().iter().cloned());
+                for missing in instrument.missing_samples(){
+                    messages.push(format!("Missing sample: {}", missing));
+                }
+                Some(LoadedInstrument::Sfz(Arc::new(instrument)))
+            },
+            Err(e) =>{
+                messages.push(format!("Could not read {}: {}", path, e));
+                None
+            }
+        }
+    }else if path.ends_with(".sf2"){
+        match Sf2Bank::from_file(Path::new(path)){
+            Ok(mut bank) =>{
+                bank.resample(sample_rate, quality);
+                Some(LoadedInstrument::Sf2(Arc::new(bank)))
+            },
+            Err(e) =>{
+                messages.push(format!("Could not read {}: {}", path, e));
+                None
+            }
+        }
+    }else{
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    #[test]
+    fn test_release(){
+        let loader = SampleLoader::new();
+        let first = Arc::new(SampleBuffer::new(1, 10, 48000.0));
+        let first_weak = Arc::downgrade(&first);
+        loader.send(LoadedInstrument::Warp(first));
+        let second = Arc::new(SampleBuffer::new(1, 10, 48000.0));
+        let second_weak = Arc::downgrade(&second);
+        loader.send(LoadedInstrument::Warp(second));
+        // Replaced before the audio thread picked it up
+        assert!(first_weak.upgrade().is_none());
+        let picked_up = loader.try_recv();
+        assert!(picked_up.is_some());
+        loader.release_unused();
+        assert!(second_weak.upgrade().is_some());
+        // The engine only lets go of its reference, the memory is freed by the loader
+        drop(picked_up);
+        assert!(second_weak.upgrade().is_some());
+        loader.release_unused();
+        assert!(second_weak.upgrade().is_none());
+    }
+}
@@ -0,0 +1,200 @@
+use crate::sample_buffer;
+use sample_buffer::SampleBuffer;
+use nih_plug::params::enums::Enum;
+use std::{f64::consts::PI, sync::Arc};
+
+/// Steps the kernel table takes between two zero crossings of the sinc
+const TABLE_RESOLUTION: usize = 512;
+
+/// How samples are converted to the session's sample rate when they're loaded
+#[derive(Clone, Copy, PartialEq, Enum, Debug)]
+pub enum ResampleQuality{
+    /// Keep the file's rate and let the voices make up the difference while playing
+    Off,
+    Fast,
+    Good,
+    Best,
+}
+
+impl ResampleQuality{
+    pub fn iter()->impl Iterator<Item = Self>{
+        [ResampleQuality::Off, ResampleQuality::Fast, ResampleQuality::Good, ResampleQuality::Best].iter().copied()
+    }
+    /// Zero crossings of the sinc on each side of the kernel's centre
+    fn zero_crossings(self)->usize{
+        match self{
+            ResampleQuality::Off => 0,
+            ResampleQuality::Fast => 8,
+            ResampleQuality::Good => 24,
+            ResampleQuality::Best => 64,
+        }
+    }
+    /// Shape of the Kaiser window. Higher values reject more aliasing but need more taps
+    fn kaiser_beta(self)->f64{
+        match self{
+            ResampleQuality::Off => 0.0,
+            ResampleQuality::Fast => 6.0,
+            ResampleQuality::Good => 8.5,
+            ResampleQuality::Best => 12.0,
+        }
+    }
+    /// Cutoff as a fraction of the lower of the two Nyquist frequencies, leaving the
+    /// filter room to roll off before anything can fold back
+    fn bandwidth(self)->f64{
+        match self{
+            ResampleQuality::Off => 1.0,
+            ResampleQuality::Fast => 0.88,
+            ResampleQuality::Good => 0.94,
+            ResampleQuality::Best => 0.97,
+        }
+    }
+}
+
+/// One side of a Kaiser windowed sinc, sampled `TABLE_RESOLUTION` times per zero crossing
+pub struct Kernel{
+    zero_crossings: usize,
+    table: Vec<f64>,
+}
+
+impl Kernel{
+    /// Builds a kernel reaching `zero_crossings` on each side, windowed with a Kaiser `beta`
+    pub fn new(zero_crossings: usize, beta: f64)->Self{
+        let length = zero_crossings * TABLE_RESOLUTION;
+        let mut table = (0..=length).map(|i|{
+            let x = i as f64 / TABLE_RESOLUTION as f64;
+            let sinc = if i == 0 {1.0} else {(PI * x).sin() / (PI * x)};
+            let position = i as f64 / length as f64;
+            sinc * bessel_i0(beta * (1.0 - position * position).sqrt()) / bessel_i0(beta)
+        }).collect::<Vec<_>>();
+        // An extra zero so the interpolation below can always read the next entry
+        table.push(0.0);
+        Kernel{zero_crossings, table}
+    }
+    /// Returns the kernel `x` zero crossings from its centre
+    pub fn at(&self, x: f64)->f64{
+        let position = x.abs() * TABLE_RESOLUTION as f64;
+        let index = position as usize;
+        if index >= self.table.len() - 1{
+            return 0.0;
+        }
+        let frac = position - index as f64;
+        self.table[index] + (self.table[index + 1] - self.table[index]) * frac
+    }
+}
+
+/// Converts a sample to `sample_rate` with a windowed sinc filter.
+///
+/// Loop points and markers in the sample's metadata are moved to the same positions
+/// in the new frames. Returns a copy if there's nothing to convert
+pub fn resample(buffer: &SampleBuffer, sample_rate: f32, quality: ResampleQuality)->SampleBuffer{
+    if !needs_resampling(buffer, sample_rate, quality){
+        return buffer.clone();
+    }
+    let ratio = sample_rate as f64 / buffer.sample_rate() as f64;
+    let length = buffer.capacity();
+    let new_length = ((length as f64 * ratio).round() as usize).max(1);
+    let num_channels = buffer.num_channels();
+    let kernel = Kernel::new(quality.zero_crossings(), quality.kaiser_beta());
+    // When the rate goes down the filter also has to remove what the new rate can't hold
+    let cutoff = ratio.min(1.0) * quality.bandwidth();
+    let reach = (kernel.zero_crossings as f64 / cutoff).ceil() as isize;
+    let mut interleaved = vec![0.0; new_length * num_channels];
+    let mut weights = Vec::with_capacity(2 * reach as usize + 1);
+    for frame in 0..new_length{
+        let position = frame as f64 / ratio;
+        let centre = position.floor() as isize;
+        let first = (centre - reach + 1).max(0);
+        let last = (centre + reach).min(length as isize - 1);
+        // The weights only depend on the position, so they're shared by every channel
+        weights.clear();
+        weights.extend((first..=last).map(|input| cutoff * kernel.at((position - input as f64) * cutoff)));
+        for channel in 0..num_channels{
+            let input = buffer.channel(channel);
+            let sum = (first..=last).zip(weights.iter())
+                .map(|(index, weight)| input.get(index as usize) as f64 * weight)
+                .sum::<f64>();
+            interleaved[frame * num_channels + channel] = sum as f32;
+        }
+    }
+    let mut metadata = buffer.metadata().clone();
+    if let Some(sustain_loop) = metadata.sustain_loop.as_mut(){
+        sustain_loop.start = scale_frame(sustain_loop.start, ratio);
+        sustain_loop.end = scale_frame(sustain_loop.end, ratio);
+    }
+    for marker in metadata.markers.iter_mut(){
+        marker.frame = scale_frame(marker.frame, ratio);
+        marker.length = scale_frame(marker.length, ratio);
+    }
+    SampleBuffer::from_interleaved(&interleaved, num_channels, sample_rate).with_metadata(metadata)
+}
+
+/// Converts a shared sample to `sample_rate`, handing back the same sample if it
+/// already plays at that rate
+pub fn resample_shared(buffer: &Arc<SampleBuffer>, sample_rate: f32, quality: ResampleQuality)->Arc<SampleBuffer>{
+    if needs_resampling(buffer, sample_rate, quality){
+        Arc::new(resample(buffer, sample_rate, quality))
+    }else{
+        buffer.clone()
+    }
+}
+
+/// Moves a frame position by the ratio between the new and the old sample rate
+pub fn scale_frame(frame: usize, ratio: f64)->usize{
+    (frame as f64 * ratio).round() as usize
+}
+
+fn needs_resampling(buffer: &SampleBuffer, sample_rate: f32, quality: ResampleQuality)->bool{
+    quality != ResampleQuality::Off && sample_rate > 0.0 && buffer.sample_rate() > 0.0 && buffer.sample_rate() != sample_rate
+}
+
+/// The zeroth order modified Bessel function of the first kind, used by the Kaiser window
+fn bessel_i0(x: f64)->f64{
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..50{
+        term *= half / k as f64;
+        sum += term * term;
+        if term * term < sum * 1e-17{
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::sample_buffer::{SampleLoop, SampleMetadata};
+    fn sine(frequency: f64, sample_rate: f32, length: usize)->Vec<f32>{
+        (0..length).map(|i| (2.0 * PI * frequency * i as f64 / sample_rate as f64).sin() as f32).collect()
+    }
+    #[test]
+    fn test_resample(){
+        let metadata = SampleMetadata{sustain_loop: Some(SampleLoop{start: 441, end: 882, bounce: false}), ..SampleMetadata::default()};
+        let buffer = SampleBuffer::from_interleaved(&sine(1000.0, 44100.0, 4410), 1, 44100.0).with_metadata(metadata);
+        for quality in [ResampleQuality::Fast, ResampleQuality::Good, ResampleQuality::Best]{
+            let resampled = resample(&buffer, 48000.0, quality);
+            assert_eq!((resampled.capacity(), resampled.sample_rate()), (4800, 48000.0));
+            assert_eq!(resampled.metadata().sustain_loop, Some(SampleLoop{start: 480, end: 960, bounce: false}));
+            let expected = sine(1000.0, 48000.0, 4800);
+            // Away from the edges, where the filter runs out of input
+            for (frame, expected) in expected.iter().enumerate().take(4600).skip(200){
+                let error = (resampled.channel(0).get(frame) - expected).abs();
+                assert!(error < 2e-3, "{:?} {} {}", quality, frame, error);
+            }
+        }
+        let unchanged = resample(&buffer, 44100.0, ResampleQuality::Best);
+        assert_eq!(unchanged.capacity(), 4410);
+        assert_eq!(resample(&buffer, 48000.0, ResampleQuality::Off).sample_rate(), 44100.0);
+    }
+    #[test]
+    fn test_downsample_removes_aliases(){
+        // 30 kHz can't exist at 48 kHz and would fold back to 18 kHz
+        let buffer = SampleBuffer::from_interleaved(&sine(30000.0, 96000.0, 9600), 1, 96000.0);
+        let resampled = resample(&buffer, 48000.0, ResampleQuality::Good);
+        assert_eq!(resampled.capacity(), 4800);
+        let peak = (500..4300).map(|frame| resampled.channel(0).get(frame).abs()).fold(0.0, f32::max);
+        assert!(peak < 1e-3, "{}", peak);
+    }
+}
@@ -1,3 +1,7 @@
+use crate::{interpolation,sample_buffer};
+use interpolation::{Interpolation, interpolate};
+use sample_buffer::SampleLoop;
+
 #[derive(Clone)]
 pub struct RingBuffer<T> {
     // TODO: fill this in.
@@ -111,6 +115,20 @@ impl RingBuffer<f32>{
         let frac = offset.fract();
         floor_samp * (1.0 - frac) + ceil_samp * frac
     }
+    /// Returns a value at a non-integer offset with the given interpolation. Frames
+    /// outside the buffer read as silence. `step` is how far the offset moves per
+    /// output sample.
+    ///
+    /// While the offset is inside `sample_loop`, frames past its ends are read from
+    /// where the loop carries on instead
+    pub fn get_interpolated(&self, offset: f32, mode: Interpolation, step: f32, sample_loop: Option<SampleLoop>)->f32{
+        let capacity = self.capacity() as isize;
+        let read = |index: isize|{
+            let index = sample_loop.map_or(index, |sample_loop| sample_loop.fold(index));
+            if (0..capacity).contains(&index) {self.get(index as usize)} else {0.0}
+        };
+        interpolate(read, offset, mode, step)
+    }
     // meant to be used similarly to pop, simply put in a offset and it will calculate the 
     // read pointer's position based on the write pointer
     pub fn pop_frac(& self, offset: f32)->f32{
@@ -0,0 +1,122 @@
+use crate::{ring_buffer,interpolation};
+use ring_buffer::RingBuffer;
+use interpolation::Interpolation;
+
+/// Holds the decoded audio of a sample with one ring buffer per channel
+#[derive(Clone)]
+pub struct SampleBuffer{
+    channels: Vec<RingBuffer<f32>>,
+    sample_rate: f32,
+    metadata: SampleMetadata,
+}
+
+/// Playback hints stored in a sample's file, such as the WAV `smpl`, `cue ` and `acid` chunks
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SampleMetadata{
+    /// The note the sample sounds at when played back unchanged
+    pub root_note: Option<u8>,
+    /// Cents the recording is above `root_note`
+    pub fine_tune: f32,
+    /// The loop to use while a note is held
+    pub sustain_loop: Option<SampleLoop>,
+    /// Named positions in the sample, sorted by frame
+    pub markers: Vec<SampleMarker>,
+}
+
+/// A loop stored in a sample's file. `end` is the first frame after the loop
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleLoop{
+    pub start: usize,
+    pub end: usize,
+    /// Plays back and forth instead of jumping from the end to the start
+    pub bounce: bool,
+}
+
+/// A cue point, which covers `length` frames when it marks a region instead of a single position
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleMarker{
+    pub frame: usize,
+    pub length: usize,
+    pub label: String,
+}
+
+impl SampleLoop{
+    /// Returns the frame that plays in place of `index` once playback is inside the loop.
+    ///
+    /// Frames past the end carry on from the start and frames before the start come from
+    /// the end. A bouncing loop reflects them back into the loop instead
+    pub fn fold(&self, index: isize)->isize{
+        let (start, end) = (self.start as isize, self.end as isize);
+        let length = end - start;
+        if length <= 0 || (start..end).contains(&index){
+            return index;
+        }
+        if self.bounce{
+            let position = (index - start).rem_euclid(2 * length);
+            start + if position <= length {position} else {2 * length - position}
+        }else{
+            start + (index - start).rem_euclid(length)
+        }
+    }
+}
+
+impl SampleBuffer{
+    /// Creates a silent buffer with `length` frames for each channel
+    /// 
+    /// Always holds at least one channel of one frame
+    pub fn new(num_channels_: usize, length: usize, sample_rate_: f32)->Self{
+        let num_channels = num_channels_.max(1);
+        SampleBuffer{
+            channels: vec![RingBuffer::<f32>::new(length.max(1)); num_channels],
+            sample_rate: sample_rate_,
+            metadata: SampleMetadata::default(),
+        }
+    }
+    /// Splits interleaved samples into one buffer per channel
+    pub fn from_interleaved(samples: &[f32], num_channels_: usize, sample_rate_: f32)->Self{
+        let num_channels = num_channels_.max(1);
+        let length = samples.len() / num_channels;
+        let mut buffer = SampleBuffer::new(num_channels, length, sample_rate_);
+        for frame in samples.chunks_exact(num_channels){
+            for (channel, sample) in buffer.channels.iter_mut().zip(frame){
+                channel.push(*sample);
+            }
+        }
+        buffer
+    }
+    /// Attaches the playback hints read from the sample's file
+    pub fn with_metadata(mut self, metadata: SampleMetadata)->Self{
+        self.metadata = metadata;
+        self
+    }
+    /// Returns the playback hints read from the sample's file
+    pub fn metadata(&self)->&SampleMetadata{
+        &self.metadata
+    }
+    /// Returns the number of frames in the sample
+    pub fn capacity(&self)->usize{
+        self.channels[0].capacity()
+    }
+    /// Returns the number of channels stored in the sample
+    pub fn num_channels(&self)->usize{
+        self.channels.len()
+    }
+    /// Returns the sample rate the audio was recorded at
+    pub fn sample_rate(&self)->f32{
+        self.sample_rate
+    }
+    /// Returns the buffer for the given channel. The index wraps around the number of
+    /// channels so a mono sample feeds every output channel
+    pub fn channel(&self, channel: usize)->&RingBuffer<f32>{
+        &self.channels[channel % self.channels.len()]
+    }
+    /// Returns the sample of a channel at a non-integer frame offset
+    pub fn get_frac(&self, channel: usize, offset: f32)->f32{
+        self.channel(channel).get_frac(offset)
+    }
+    /// Returns the sample of a channel at a non-integer frame offset with the given interpolation,
+    /// reading across the seam of `sample_loop` if the offset is inside it
+    pub fn get_interpolated(&self, channel: usize, offset: f32, mode: Interpolation, step: f32, sample_loop: Option<SampleLoop>)->f32{
+        self.channel(channel).get_interpolated(offset, mode, step, sample_loop)
+    }
+}
@@ -1,100 +1,435 @@
-use crate::{sampler_voice,ring_buffer,adsr};
-use sampler_voice::{SamplerVoice,SustainModes,VoiceType};
-use ring_buffer::RingBuffer;
-use std::{collections::HashMap, path::Path};
-use hound::SampleFormat;
+use crate::{sampler_voice,sample_buffer,adsr,crossfade,loader,sfz,sf2,decoder,interpolation};
+use sampler_voice::{SamplerVoice,SustainModes,VoiceType,LoopWhile,RegionNote};
+use sample_buffer::{SampleBuffer,SampleLoop};
+use crossfade::Crossfade;
+use loader::LoadedInstrument;
+use sfz::{SfzInstrument,SfzLoopMode,SfzOffMode,SfzRegion};
+use sf2::Sf2Bank;
+use decoder::{DecodedAudio,DecodeError};
+use interpolation::Interpolation;
+use std::{collections::HashMap, sync::Arc};
 use adsr::AdsrState;
-use sofiza::{Instrument, Opcode};
 
 #[derive(Clone)]
 pub struct SamplerEngine{
     num_voices: u8,
-    sound_bank: HashMap<u8,(String,f32,RingBuffer<f32>, SamplerVoice)>,
+    sound_bank: HashMap<u8,(String,f32,SampleBuffer, SamplerVoice)>,
     file_names: Vec<String>,
-    warp_buffer: RingBuffer<f32>,
+    warp_buffer: Option<Arc<SampleBuffer>>,
+    /// The sustain loop stored in the warp sample's file. It's played instead of the sustain
+    /// params until the user changes them
+    file_loop: Option<SampleLoop>,
     sampler_mode: SamplerMode,
     warp_voices: Vec<SamplerVoice>,
     sample_rate: f32,
     num_channels: usize,
     warp_sr_scalar: f32,
-    instrument: Instrument,
+    /// The sfz or SoundFont instrument being played. None until one is loaded, so the
+    /// audio thread never has to free a placeholder
+    instrument: Option<Arc<SfzInstrument>>,
+    params: Option<EngineParams>,
+    pending: Option<LoadedInstrument>,
+    swap_fader: Crossfade,
+    random_state: u32,
+    /// State of every midi note, used by sfz release and legato regions
+    key_states: [KeyState; 128],
+    /// Number of frames rendered so far
+    frame_count: u64,
+    /// The last sfz key switch that was pressed
+    last_keyswitch: Option<u8>,
+    /// The latest value of every midi CC, from 0 to 1
+    cc_values: [f32; 128],
+    /// The SoundFont being played in Sf2 mode, with the index of its selected preset
+    sf2_bank: Option<Arc<Sf2Bank>>,
+    sf2_preset: usize,
+    /// The warp base note, used when the warp sample's file doesn't give its own root note
+    warp_base: u8,
 }
 #[derive(PartialEq,Clone)]
 pub enum SamplerMode{
     Warp, // For when you just load one sample and want it to be pitch warped
     Assign, // For when you load multiple samples and assign them to midi notes
     Sfz, // For when you load an sfz file
+    Sf2, // For when you load a SoundFont, which plays the regions of its selected preset
+}
+/// What the engine remembers about a key between its note on and note off
+#[derive(Clone, Copy, Default)]
+struct KeyState{
+    held: bool,
+    velocity: f32,
+    /// Frame the key was pressed on
+    on_frame: u64,
+}
+/// Time in seconds to fade out the old instrument and fade in a newly loaded one
+const SWAP_FADE_TIME: f32 = 0.01;
+/// The largest number of output channels `render` will fill
+pub const MAX_OUTPUT_CHANNELS: usize = 8;
+
+/// Events handled by `SamplerEngine::render`. 
+/// 
+/// timing is the offset in samples from the start of the rendered block
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EngineEvent{
+    NoteOn{timing: u32, note: u8, velocity: f32},
+    NoteOff{timing: u32, note: u8},
+    /// A midi CC with its value from 0 to 1
+    MidiCC{timing: u32, cc: u8, value: f32},
+}
+impl EngineEvent{
+    /// Returns the offset in samples from the start of the block
+    pub fn timing(&self)->u32{
+        match self{
+            EngineEvent::NoteOn{timing, ..} => *timing,
+            EngineEvent::NoteOff{timing, ..} => *timing,
+            EngineEvent::MidiCC{timing, ..} => *timing,
+        }
+    }
+}
+/// Parameters shared by all the voices of the warp and sfz samplers. 
+/// 
+/// attack, decay, release and fade_time are in seconds, sustain is 0-1, 
+/// and the points are in percent of the sample length
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct EngineParams{
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    pub start_point: f32,
+    pub end_point: f32,
+    pub sus_start: f32,
+    pub sus_end: f32,
+    pub sus_mode: SustainModes,
+    pub fade_time: f32,
+    pub num_voices: u8,
+    pub interpolation: Interpolation,
 }
 
 impl SamplerEngine{
     pub fn new(sample_rate_: f32, num_channels_: usize) -> Self{
         
+        // Built here so the first sinc voice doesn't build it on the audio thread
+        interpolation::prepare_tables();
         let files = vec!["".to_string();100];
-        let buff = RingBuffer::<f32>::new(1);
         let voices_ = vec![SamplerVoice::new(num_channels_,sample_rate_,64,VoiceType::Warp);6];
 
         let mut engine = SamplerEngine{
             num_voices: 6,
             sound_bank: HashMap::with_capacity(30),
             file_names: files,
-            warp_buffer: buff,
+            warp_buffer: None,
+            file_loop: None,
             sampler_mode: SamplerMode::Warp,
             warp_voices: voices_,
             sample_rate: sample_rate_,
             num_channels: num_channels_,
             warp_sr_scalar: sample_rate_,
-            instrument: Instrument::new(),
+            instrument: None,
+            params: None,
+            pending: None,
+            swap_fader: Crossfade::new(sample_rate_, SWAP_FADE_TIME, SWAP_FADE_TIME),
+            random_state: 0x9E37_79B9,
+            key_states: [KeyState::default(); 128],
+            frame_count: 0,
+            last_keyswitch: None,
+            cc_values: [0.0; 128],
+            sf2_bank: None,
+            sf2_preset: 0,
+            warp_base: 60,
         };
         engine.file_names.clear();
         engine
     }
-    pub fn process(&mut self)->f32{
-        let mut out_samp = 0.0;
+    /// Renders one frame of audio into `frame`, one value per output channel.
+    /// 
+    /// Every voice is advanced exactly once per call
+    pub fn process(&mut self, frame: &mut [f32]){
+        frame.fill(0.0);
+        self.frame_count += 1;
+        if self.pending.is_some() && self.swap_fader.is_faded_out(){
+            self.install_pending();
+        }
         match self.sampler_mode{
             SamplerMode::Warp =>{
-                for voice in self.warp_voices.iter_mut(){
-                    out_samp += voice.process(&mut self.warp_buffer, 
-                                                self.warp_sr_scalar);
+                if let Some(buffer) = &self.warp_buffer{
+                    for voice in self.warp_voices.iter_mut(){
+                        voice.process(buffer, self.warp_sr_scalar, frame);
+                    }
                 }
             },
             SamplerMode::Assign =>{
                 for (_note, (_name,sr_scalar,buff,voice)) in self.sound_bank.iter_mut(){
-                    out_samp += voice.process(buff,*sr_scalar);
+                    voice.process(buff,*sr_scalar, frame);
                 }
             },
-            SamplerMode::Sfz =>{
+            SamplerMode::Sfz | SamplerMode::Sf2 =>{
                 for voice in self.warp_voices.iter_mut(){
-                    out_samp += voice.process_sfz(self.warp_sr_scalar);
+                    voice.process_sfz(frame);
+                }
+            }
+        }
+        let swap_gain = self.swap_fader.get_next_sample();
+        if swap_gain != 1.0{
+            for sample in frame.iter_mut(){
+                *sample *= swap_gain;
+            }
+        }
+    }
+    /// Queues an instrument that was loaded on another thread. 
+    /// 
+    /// The engine fades out, swaps the instrument in once silent and fades back in,
+    /// so loading never interrupts the audio thread
+    pub fn swap_instrument(&mut self, instrument: LoadedInstrument){
+        if self.pending.is_none(){
+            self.swap_fader.start_fade_out();
+        }
+        self.pending = Some(instrument);
+    }
+    /// Returns whether an instrument is waiting for the fade out to finish before it's swapped in
+    pub fn is_swapping(&self)->bool{
+        self.pending.is_some()
+    }
+    /// Replaces the current instrument with the pending one and silences the voices
+    fn install_pending(&mut self){
+        if let Some(instrument) = self.pending.take(){
+            self.stop_all_voices();
+            match instrument{
+                LoadedInstrument::Warp(buffer) =>{
+                    self.set_mode(SamplerMode::Warp);
+                    self.sf2_bank = None;
+                    self.last_keyswitch = None;
+                    for voice in self.warp_voices.iter_mut(){
+                        voice.clear_region_settings();
+                    }
+                    self.set_warp_buffer(buffer);
+                },
+                LoadedInstrument::Sfz(instrument) =>{
+                    self.set_mode(SamplerMode::Sfz);
+                    self.sf2_bank = None;
+                    self.last_keyswitch = instrument.default_keyswitch();
+                    for (cc, value) in instrument.cc_defaults(){
+                        self.cc_values[*cc as usize] = *value;
+                    }
+                    self.instrument = Some(instrument);
+                },
+                LoadedInstrument::Sf2(bank) =>{
+                    self.set_mode(SamplerMode::Sf2);
+                    self.last_keyswitch = None;
+                    self.sf2_preset = self.sf2_preset.min(bank.presets().len().saturating_sub(1));
+                    if let Some(preset) = bank.preset(self.sf2_preset){
+                        self.instrument = Some(preset.instrument.clone());
+                    }
+                    self.sf2_bank = Some(bank);
                 }
             }
+            self.swap_fader.start_fade_in();
         }
-        out_samp
+    }
+    /// Selects the SoundFont preset to play, by its index in the bank's preset list.
+    /// 
+    /// The change fades out and back in like loading a new instrument
+    pub fn set_sf2_preset(&mut self, index: usize){
+        if index == self.sf2_preset{
+            return;
+        }
+        self.sf2_preset = index;
+        // A pending instrument picks up the new index when it is installed
+        if self.pending.is_none() && self.sampler_mode == SamplerMode::Sf2{
+            if let Some(bank) = &self.sf2_bank{
+                self.swap_instrument(LoadedInstrument::Sf2(bank.clone()));
+            }
+        }
+    }
+    /// Immediately silences every voice
+    pub fn stop_all_voices(&mut self){
+        for voice in self.warp_voices.iter_mut(){
+            voice.adsr.reset();
+        }
+        for (_note, (_name,_sr_scalar,_buff,voice)) in self.sound_bank.iter_mut(){
+            voice.adsr.reset();
+        }
+    }
+    /// Applies a note or CC event straight away, ignoring its timing
+    pub fn handle_event(&mut self, event: EngineEvent){
+        match event{
+            EngineEvent::NoteOn{note, velocity, ..} => self.note_on(note, velocity),
+            EngineEvent::NoteOff{note, ..} => self.note_off(note),
+            EngineEvent::MidiCC{cc, value, ..} => self.set_cc(cc, value),
+        }
+    }
+    /// Renders a block of audio into `out`, with one slice per output channel.
+    /// 
+    /// `events` must be sorted by timing. The block is split at each event's offset
+    /// so notes start and stop on the exact sample they were sent on
+    pub fn render(&mut self, out: &mut [&mut [f32]], events: &[EngineEvent]){
+        let num_samples = out.iter().map(|channel| channel.len()).min().unwrap_or(0);
+        let mut block_start = 0;
+        for event in events{
+            let timing = (event.timing() as usize).clamp(block_start, num_samples);
+            self.render_range(out, block_start, timing);
+            self.handle_event(*event);
+            block_start = timing;
+        }
+        self.render_range(out, block_start, num_samples);
+    }
+    /// Renders the samples between start and end of each channel in `out`
+    fn render_range(&mut self, out: &mut [&mut [f32]], start: usize, end: usize){
+        let num_channels = out.len().min(MAX_OUTPUT_CHANNELS);
+        let mut frame = [0.0_f32; MAX_OUTPUT_CHANNELS];
+        for i in start..end{
+            self.process(&mut frame[..num_channels]);
+            for (channel, value) in out.iter_mut().zip(frame.iter()){
+                channel[i] = *value;
+            }
+        }
+    }
+    /// Updates the parameters of the warp and sfz voices. 
+    /// 
+    /// Only the parameters that differ from the last call are passed on to the voices,
+    /// so this can be called once per block without any cost while nothing is moving
+    pub fn set_params(&mut self, params: EngineParams){
+        let old = match self.params{
+            Some(old) if old.num_voices == params.num_voices => old,
+            _ => {
+                // New voices have to be given every parameter
+                self.set_num_voices(params.num_voices);
+                self.apply_params(params);
+                return;
+            }
+        };
+        if (old.attack, old.decay, old.sustain, old.release) != 
+            (params.attack, params.decay, params.sustain, params.release){
+            self.set_adsr_warp(params.attack, params.decay, params.sustain, params.release);
+        }
+        if (old.start_point, old.end_point) != (params.start_point, params.end_point){
+            self.set_points_warp(params.start_point, params.end_point);
+        }
+        if (old.sus_mode, old.sus_start, old.sus_end) != (params.sus_mode, params.sus_start, params.sus_end){
+            // The user has taken over the loop
+            self.file_loop = None;
+        }
+        if old.sus_mode != params.sus_mode{
+            self.set_sus_looping_warp(params.sus_mode);
+        }
+        if (old.start_point, old.end_point, old.sus_start, old.sus_end) != 
+            (params.start_point, params.end_point, params.sus_start, params.sus_end){
+            // Sustain points are clamped to the start and end points, so they follow them
+            self.set_sus_points_warp(params.sus_start, params.sus_end);
+            self.apply_file_loop();
+        }
+        if old.fade_time != params.fade_time{
+            self.set_fade_time_warp(params.fade_time);
+        }
+        if old.interpolation != params.interpolation{
+            self.set_interpolation(params.interpolation);
+        }
+        self.params = Some(params);
+    }
+    /// Passes every parameter on to the voices, followed by the warp sample's own settings
+    fn apply_params(&mut self, params: EngineParams){
+        self.set_adsr_warp(params.attack, params.decay, params.sustain, params.release);
+        self.set_points_warp(params.start_point, params.end_point);
+        self.set_sus_looping_warp(params.sus_mode);
+        self.set_sus_points_warp(params.sus_start, params.sus_end);
+        self.set_fade_time_warp(params.fade_time);
+        self.set_interpolation(params.interpolation);
+        self.params = Some(params);
+        self.apply_warp_metadata();
+    }
+    /// Uses the root note, tuning and loop stored in the warp sample's file.
+    /// 
+    /// The file's loop replaces the sustain points and mode until they are changed again
+    fn apply_warp_metadata(&mut self){
+        if self.sampler_mode != SamplerMode::Warp{
+            return;
+        }
+        let Some(buffer) = &self.warp_buffer else{
+            return;
+        };
+        let metadata = buffer.metadata();
+        let base_note = metadata.root_note.unwrap_or(self.warp_base);
+        for voice in self.warp_voices.iter_mut(){
+            voice.set_base_midi(base_note);
+            voice.set_pitch(100.0, -metadata.fine_tune);
+        }
+        self.apply_file_loop();
+    }
+    /// Plays the loop from the warp sample's file, if it has one the user hasn't replaced
+    fn apply_file_loop(&mut self){
+        let Some(sample_loop) = self.file_loop else{
+            return;
+        };
+        if self.sampler_mode != SamplerMode::Warp{
+            return;
+        }
+        for voice in self.warp_voices.iter_mut(){
+            voice.set_sus_points_in_frames(sample_loop.start as f32, sample_loop.end as f32);
+            voice.set_sus_loop_mode(if sample_loop.bounce {SustainModes::LoopBounce} else {SustainModes::LoopWrap});
+        }
+    }
+    /// Returns whether the warp voices are playing the loop from the sample's file rather
+    /// than the sustain params
+    pub fn uses_file_loop(&self)->bool{
+        self.sampler_mode == SamplerMode::Warp && self.file_loop.is_some()
+    }
+    /// Sets up a voice's playback range, looping, level and envelope from an sfz region.
+    /// 
+    /// The velocity and key crossfades are worked out for `note`.
+    /// `held_time` is how long the key was held in seconds when a release region is started
+    /// on a note off. Release regions are turned down by `rt_decay` and ignore note offs
+    fn apply_region(&mut self, voice_id: usize, region_id: usize, region: &SfzRegion, note: u8, velocity: u8, held_time: Option<f32>){
+        let defaults = self.params.map(|params| (params.attack, params.decay, params.sustain, params.release));
+        let voice = &mut self.warp_voices[voice_id];
+        let (delay, attack, hold, decay, sustain, release) = 
+            region.envelope(defaults.unwrap_or_else(|| voice.adsr.get_adsr()));
+        voice.set_adsr(attack, decay, sustain, release);
+        voice.adsr.set_delay(delay);
+        voice.adsr.set_hold(hold);
+        let release_attenuation = held_time.map_or(1.0, |held_time| region.release_attenuation(held_time));
+        let attenuation = release_attenuation * region.crossfade_gain(note, velocity);
+        let region_note = RegionNote{id: region_id, velocity, attenuation};
+        voice.region = Some(region_note);
+        apply_region_modulation(voice, region, region_note, &self.cc_values);
+        let (start, end, loop_start, loop_end) = region.playback_range(voice.internal_capacity());
+        voice.set_points_in_frames(start as f32, end as f32, loop_start as f32, loop_end as f32);
+        let (sus_mode, loop_while, one_shot) = match region.loop_mode(){
+            SfzLoopMode::NoLoop => (SustainModes::NoLoop, LoopWhile::SustainStage, false),
+            SfzLoopMode::OneShot => (SustainModes::NoLoop, LoopWhile::SustainStage, true),
+            SfzLoopMode::LoopContinuous => (SustainModes::LoopWrap, LoopWhile::Always, false),
+            SfzLoopMode::LoopSustain => (SustainModes::LoopWrap, LoopWhile::KeyHeld, false),
+        };
+        voice.set_sus_loop_mode(sus_mode);
+        voice.set_loop_behaviour(loop_while, one_shot || held_time.is_some());
+        voice.group = region.group;
+        voice.off_by = region.off_by;
+        voice.fast_off = region.off_mode == SfzOffMode::Fast;
     }
     ///Add a file to the paths of files saved in the file names
     /// and load file into the warp buffer.
-    pub fn add_to_paths_and_load(&mut self, file_path: &str){
-        if file_path.ends_with(".wav"){
-            self.warp_sr_scalar =  fill_warp_buffer(&mut self.warp_buffer, file_path)/
-                                self.sample_rate;
+    pub fn add_to_paths_and_load(&mut self, file_path: &str)->Result<(), DecodeError>{
+        if decoder::is_supported(file_path){
+            self.load_warp_buffer(file_path)?;
             self.file_names.push(file_path.to_string());
         }
+        Ok(())
     }
     ///Add a file to the paths of files saved in the file names.
     pub fn add_file_to_paths(&mut self, file_path: &str){
-        if file_path.ends_with(".wav"){
+        if decoder::is_supported(file_path){
             self.file_names.push(file_path.to_string());
         }
     }
     ///Load a file into the warp buffer from the list of filepaths that have been added
     /// 
     /// idx will wrap around the size of the file_paths buffer
-    pub fn load_file_by_index(&mut self, idx: usize){
+    pub fn load_file_by_index(&mut self, idx: usize)->Result<(), DecodeError>{
         if self.file_names.len() > 0{
             let new_idx = idx % self.file_names.len();
-            if let Some(file_path) = self.file_names.get(new_idx){
-                self.warp_sr_scalar = fill_warp_buffer(&mut self.warp_buffer, &file_path)/self.sample_rate;
+            if let Some(file_path) = self.file_names.get(new_idx).cloned(){
+                self.load_warp_buffer(&file_path)?;
             }
         }
+        Ok(())
     }
 
     pub fn get_file_name_by_index(&mut self, idx: usize)->Option<String>{
@@ -103,35 +438,47 @@ impl SamplerEngine{
     }
     ///Load file from path into the warp buffer without loading 
     /// into the file names.
-    pub fn load_file_from_path(&mut self, file_path: &str){
-        self.warp_sr_scalar =  fill_warp_buffer(&mut self.warp_buffer, file_path)/
-                                self.sample_rate;
+    pub fn load_file_from_path(&mut self, file_path: &str)->Result<(), DecodeError>{
+        self.load_warp_buffer(file_path)
+    }
+    /// Fills the warp buffer from a file, keeping the current buffer if the file can't be decoded
+    fn load_warp_buffer(&mut self, file_path: &str)->Result<(), DecodeError>{
+        let (buffer, _sample_rate) = create_buffer(file_path)?;
+        self.set_warp_buffer(Arc::new(buffer));
+        Ok(())
+    }
+    /// Replaces the warp buffer and refreshes the voice points for its new length
+    fn set_warp_buffer(&mut self, buffer: Arc<SampleBuffer>){
+        self.warp_sr_scalar = buffer.sample_rate() / self.sample_rate;
+        self.file_loop = buffer.metadata().sustain_loop;
+        self.warp_buffer = Some(buffer);
+        if let Some(params) = self.params{
+            self.apply_params(params);
+        }
     }
     /// Assigns an audio file to a midi note for the sound bank. (Assign mode)
     /// 
-    /// Will add file to paths if not already there
-    pub fn assign_file_to_midi(&mut self, file_path: &str, note: u8){
+    /// Will add file to paths if not already there. The note is left as it was if the
+    /// file can't be decoded
+    pub fn assign_file_to_midi(&mut self, file_path: &str, note: u8)->Result<(), DecodeError>{
         if !self.file_names.contains(&file_path.to_string()){
             self.add_file_to_paths(file_path);
         }
-        let (buff,sr) = create_buffer(file_path);
+        let (buff,sr) = create_buffer(file_path)?;
         let sr_scalar = sr / self.sample_rate;
-        self.sound_bank.insert(note,(file_path.to_string(),sr_scalar,buff,
-                            SamplerVoice::new(self.num_channels,self.sample_rate,note,VoiceType::Assign))); 
-    }
-
-    /// Load an SFZ file and create an instrument
-    pub fn load_sfz(&mut self, file_path: &str){
-        let result = Instrument::from_file(Path::new(file_path));
-        match result {
-            Ok(instrument) => self.instrument = instrument,
-            Err(_e) => {}
+        let mut voice = SamplerVoice::new(self.num_channels,self.sample_rate,note,VoiceType::Assign);
+        if let Some(params) = self.params{
+            voice.set_interpolation(params.interpolation);
         }
+        self.sound_bank.insert(note,(file_path.to_string(),sr_scalar,buff,voice));
+        Ok(())
     }
 
     /// Triggers a "note on" message and allocates a voice, 
     ///  stealing if necessary
     pub fn note_on(&mut self, note: u8, velocity: f32){
+        let other_keys_held = self.other_keys_held(note);
+        self.key_states[(note as usize).min(127)] = KeyState{held: true, velocity, on_frame: self.frame_count};
         match self.sampler_mode {
             SamplerMode::Warp =>{
                 let voice_id = self.get_voice_id();
@@ -145,97 +492,20 @@ impl SamplerEngine{
                     }
                 } 
             },
-            SamplerMode::Sfz =>{
-                let instrument = self.instrument.clone();
-                for region in instrument.regions.iter(){
-                    let mut lokey = u8::MIN;
-                    let mut hikey = u8::MAX;
-                    let mut lovel = f32::MIN;
-                    let mut hivel = f32::MAX;
-                    match region.opcodes.get("lokey") {
-                        Some(value) => {
-                            match value {
-                                Opcode::lokey(value) => {
-                                    lokey = *value;
-                                },
-                                _ => println!("Something else")
-                            }
-                        },
-                        None => {}
-                    }
-                    match region.opcodes.get("hikey") {
-                        Some(value) => {
-                            match value {
-                                Opcode::hikey(value) => {
-                                    hikey = *value;
-                                },
-                                _ => println!("Something else")
-                            }
-                        },
-                        None => {}
-                    }
-                    match region.opcodes.get("lovel") {
-                        Some(value) => {
-                            match value {
-                                Opcode::lovel(value) => {
-                                    lovel = *value as f32;
-                                },
-                                _ => println!("Something else")
-                            }
-                        },
-                        None => {}
-                    }
-                    match region.opcodes.get("hivel") {
-                        Some(value) => {
-                            match value {
-                                Opcode::hivel(value) => {
-                                    hivel = *value as f32;
-                                },
-                                _ => println!("Something else")
-                            }
-                        },
-                        None => {}
-                    }
-                    // Conditional filters
-                    if note >= lokey && note <= hikey && velocity*127.0 >= lovel && velocity*127.0 <= hivel {
-                        let voice_id = self.get_voice_id();
-                        match region.opcodes.get("sample") {
-                            Some(value) => {
-                                match value {
-                                    Opcode::sample(value) => {
-                                        match value.to_str() {
-                                            Some(file_path) => {
-                                                let result = create_buffer(file_path);
-                                                self.warp_sr_scalar = result.1/self.sample_rate;
-                                                self.warp_voices[voice_id].internal_buffer = result.0.clone();
-                                            },
-                                            None => { panic!("Could not convert value to string") }
-                                        }
-                                    },
-                                    _ => println!("Something else")
-                                }
-                            },
-                            None => {}
-                        }
-                        match region.opcodes.get("pitch_keycenter") {
-                            Some(value) => {
-                                match value {
-                                    Opcode::pitch_keycenter(value) => {
-                                        self.warp_voices[voice_id].base_midi = *value;
-                                    },
-                                    _ => println!("Something else")
-                                }
-                            },
-                            None => {}
-                        }
-                        self.warp_voices[voice_id].note_on(note, velocity);
-                    }
+            SamplerMode::Sfz | SamplerMode::Sf2 =>{
+                if self.is_keyswitch(note){
+                    self.last_keyswitch = Some(note);
+                }else{
+                    self.trigger_sfz_regions(note, velocity, false, other_keys_held);
                 }
             }
         }
     }
     /// Triggers a note off message
     pub fn note_off(&mut self, note: u8){
+        let key_state = &mut self.key_states[(note as usize).min(127)];
+        let was_held = key_state.held;
+        key_state.held = false;
         match self.sampler_mode {
             SamplerMode::Warp =>{
                 for voice in self.warp_voices.iter_mut(){
@@ -253,18 +523,121 @@ impl SamplerEngine{
                     }
                 }               
             },
-            SamplerMode::Sfz =>{
+            SamplerMode::Sfz | SamplerMode::Sf2 =>{
+                // Every region of the note is released, release regions started
+                // by an earlier note off ignore it
                 for voice in self.warp_voices.iter_mut(){
                     if voice.midi_note == note{
                         voice.note_off();
-                        break;
                     }
                 }
+                if was_held && !self.is_keyswitch(note){
+                    let velocity = self.key_states[(note as usize).min(127)].velocity;
+                    let other_keys_held = self.other_keys_held(note);
+                    self.trigger_sfz_regions(note, velocity, true, other_keys_held);
+                }
             }
         }
     }
+    /// Starts a voice for each sfz region that plays on a note on, or a note off if `release` is true
+    fn trigger_sfz_regions(&mut self, note: u8, velocity: f32, release: bool, other_keys_held: bool){
+        // Cloning the Arc lets the regions be read while the voices are changed
+        let Some(instrument) = self.instrument.clone() else{
+            return;
+        };
+        let velocity_midi = (velocity * 127.0).round() as u8;
+        let random = self.next_random();
+        let key_state = self.key_states[(note as usize).min(127)];
+        let held_time = release.then(|| (self.frame_count - key_state.on_frame) as f32 / self.sample_rate);
+        let (last_keyswitch, key_states) = (self.last_keyswitch, self.key_states);
+        let cc_values = self.cc_values;
+        let regions = || instrument.regions_for(note, velocity_midi, random)
+            .filter(|(_, region)| region.trigger.plays_on(release, other_keys_held))
+            .filter(|(_, region)| region.is_switched_on(last_keyswitch, |key| key_states[key as usize].held))
+            .filter(|(_, region)| region.is_in_cc_range(&cc_values));
+        // Stop the voices turned off by the new regions first, so regions started
+        // together never stop each other
+        for (_, region) in regions(){
+            self.stop_group(region.group);
+        }
+        for (region_id, region) in regions(){
+            if let Some(polyphony) = region.polyphony{
+                self.limit_group_polyphony(region.group, polyphony);
+            }
+            let voice_id = self.get_voice_id();
+            self.warp_voices[voice_id].internal_buffer = Some(instrument.sample(region).clone());
+            self.apply_region(voice_id, region_id, region, note, velocity_midi, held_time);
+            self.warp_voices[voice_id].base_midi = region.pitch_keycenter;
+            self.warp_voices[voice_id].start_frame = self.frame_count;
+            self.warp_voices[voice_id].note_on(note, velocity);
+        }
+        if !release{
+            instrument.advance_sequences(note, velocity_midi);
+        }
+    }
+    /// Stores a midi CC value (0-1) and updates the sfz voices it modulates
+    pub fn set_cc(&mut self, cc: u8, value: f32){
+        self.cc_values[(cc as usize).min(127)] = value.clamp(0.0, 1.0);
+        if !self.plays_regions(){
+            return;
+        }
+        let Some(instrument) = &self.instrument else{
+            return;
+        };
+        for voice in self.warp_voices.iter_mut(){
+            if let Some(region_note) = voice.region{
+                if voice.is_active(){
+                    let region = instrument.region(region_note.id);
+                    apply_region_modulation(voice, region, region_note, &self.cc_values);
+                }
+            }
+        }
+    }
+    /// Returns whether a note is a key switch of the current instrument
+    fn is_keyswitch(&self, note: u8)->bool{
+        self.instrument.as_ref().is_some_and(|instrument| instrument.is_keyswitch(note))
+    }
+    /// Returns the last sfz key switch that was pressed, which selects the articulation
+    pub fn last_keyswitch(&self)->Option<u8>{
+        self.last_keyswitch
+    }
+    /// Stops every voice whose off_by group is `group`
+    fn stop_group(&mut self, group: i32){
+        for voice in self.warp_voices.iter_mut(){
+            if voice.off_by == Some(group) && voice.is_active(){
+                voice.choke(voice.fast_off);
+            }
+        }
+    }
+    /// Makes room for a new voice in `group` by quickly stopping the oldest
+    /// of its voices once `polyphony` of them are playing. Released voices don't count
+    fn limit_group_polyphony(&mut self, group: i32, polyphony: u32){
+        let playing = |voice: &&mut SamplerVoice| {
+            voice.group == group && voice.adsr.state != AdsrState::Release && voice.adsr.state != AdsrState::Inactive
+        };
+        // The new voice always plays, so a limit of zero stops the same voices as a limit of one
+        let limit = polyphony.max(1) as usize;
+        let count = self.warp_voices.iter_mut().filter(playing).count();
+        for _ in limit..=count{
+            if let Some(voice) = self.warp_voices.iter_mut().filter(playing).min_by_key(|voice| voice.start_frame){
+                voice.choke(true);
+            }
+        }
+    }
+    /// Returns whether any key other than `note` is held down. Key switches only choose
+    /// articulations, so holding one doesn't make the next note legato
+    fn other_keys_held(&self, note: u8)->bool{
+        self.key_states.iter().enumerate().any(|(key, state)| {
+            state.held && key != note as usize && !self.is_keyswitch(key as u8)
+        })
+    }
     /// Sets the attack, decay, sustain, and release for all the warp sample voices
+    /// 
+    /// Sfz voices take their envelope from the region at note on instead
     pub fn set_adsr_warp(&mut self, attack_: f32, decay_: f32, sustain_: f32, release_: f32){
+        if self.plays_regions(){
+            return;
+        }
         for voice in self.warp_voices.iter_mut(){
             voice.set_adsr(attack_, decay_, sustain_, release_);
         }
@@ -273,9 +646,6 @@ impl SamplerEngine{
     pub fn set_adsr_assign(&mut self, attack_: f32, decay_: f32, sustain_: f32, release_: f32, note_of_assigned: u8){
         if let Some((_file_name, _sr_scalar, _buff, voice)) = self.sound_bank.get_mut(&note_of_assigned) {
             voice.set_adsr(attack_,decay_,sustain_,release_);
-        } else {
-            // Entry does not exist, handle the error (e.g., log an error message)
-            eprintln!("Entry for note {} does not exist in sound bank", note_of_assigned);
         }
     }
     /// Returns attack, decay, sustain, release values for the warping sampler
@@ -313,27 +683,32 @@ impl SamplerEngine{
     pub fn set_mode(&mut self, mode: SamplerMode){
         self.sampler_mode = mode;
     }
-    /// Sets the note for the warping to be based on
+    /// Sets the note for the warping to be based on. A root note stored in the
+    /// warp sample's file takes its place while that sample is loaded
     pub fn set_warp_base(&mut self, base_note: u8){
+        self.warp_base = base_note;
+        let base_note = self.warp_buffer.as_ref()
+            .and_then(|buffer| buffer.metadata().root_note)
+            .unwrap_or(base_note);
         for voice in self.warp_voices.iter_mut(){
             match self.sampler_mode {
                 SamplerMode::Warp => {voice.set_base_midi(base_note);},
                 SamplerMode::Assign => {},
-                SamplerMode::Sfz => {}
+                SamplerMode::Sfz | SamplerMode::Sf2 => {}
             }
         }
     }
     /// Returns the internal buffer for the warping sampler for use in the gui
-    pub fn get_warp_buffer(& self)-> RingBuffer<f32>{
+    pub fn get_warp_buffer(& self)-> Option<Arc<SampleBuffer>>{
         self.warp_buffer.clone()
     }
     /// Returns the buffer for the sample assigned to the given note
-    pub fn get_assign_buffer(&mut self, note_of_assigned: u8 )->RingBuffer<f32>{
+    pub fn get_assign_buffer(&mut self, note_of_assigned: u8 )->SampleBuffer{
         if let Some((_file_name, _sr_scalar, buff, voice)) = self.sound_bank.get_mut(&note_of_assigned) {
             // Entry exists, update the points
             buff.clone()
         } else {
-            RingBuffer::<f32>::new(1)
+            SampleBuffer::new(self.num_channels, 1, self.sample_rate)
         }
     }
     /// Sets the start and end points for each of the voices for the warping sampler
@@ -342,11 +717,12 @@ impl SamplerEngine{
     ///  
     /// If the start point is greater than the endpoint, the playback will be reversed
     pub fn set_points_warp(&mut self, start_point: f32, end_point: f32){
+        let warp_capacity = self.warp_capacity();
         for voice in self.warp_voices.iter_mut(){
             match self.sampler_mode {
-                SamplerMode::Warp => {voice.set_start_and_end_point(start_point, end_point, self.warp_buffer.capacity());},
+                SamplerMode::Warp => {voice.set_start_and_end_point(start_point, end_point, warp_capacity);},
                 SamplerMode::Assign => {},
-                SamplerMode::Sfz => {voice.set_start_and_end_point(start_point, end_point, voice.internal_buffer.capacity());}
+                SamplerMode::Sfz | SamplerMode::Sf2 => {} // Sfz regions set their own points
             }
         }
     }
@@ -355,9 +731,9 @@ impl SamplerEngine{
     ///  Returns tuple in the format: (start_point, end_point)
     pub fn get_points_warp(&mut self)->(f32,f32){
         match self.sampler_mode {
-            SamplerMode::Warp => {self.warp_voices[0].get_points(self.warp_buffer.capacity())},
+            SamplerMode::Warp => {let capacity = self.warp_capacity(); self.warp_voices[0].get_points(capacity)},
             SamplerMode::Assign => {(0.0,0.0)},
-            SamplerMode::Sfz => {(0.0,0.0)}
+            SamplerMode::Sfz | SamplerMode::Sf2 => {(0.0,0.0)}
         }
     }
     /// Sets the start and end points for an assigned sampler voice
@@ -371,9 +747,6 @@ impl SamplerEngine{
         if let Some((_file_name, _sr_scalar, buff, voice)) = self.sound_bank.get_mut(&note_of_assigned) {
             // Entry exists, update the points
             voice.set_start_and_end_point(start_point, end_point, buff.capacity());
-        } else {
-            // Entry does not exist, handle the error (e.g., log an error message)
-            eprintln!("Entry for note {} does not exist in sound bank", note_of_assigned);
         }
     }
     /// Gets the start and end points (in percent) of the voice assigned to the given midi note
@@ -390,11 +763,12 @@ impl SamplerEngine{
     /// Sets the start and end points of the warp buffer's sustain looping. Values will be clamped
     /// within start and end points of the sample as a whole
     pub fn set_sus_points_warp(&mut self, start_point: f32, end_point: f32){
+        let warp_capacity = self.warp_capacity();
         for voice in self.warp_voices.iter_mut(){
             match self.sampler_mode {
-                SamplerMode::Warp => {voice.set_sus_points(start_point, end_point, self.warp_buffer.capacity());},
+                SamplerMode::Warp => {voice.set_sus_points(start_point, end_point, warp_capacity);},
                 SamplerMode::Assign => {},
-                SamplerMode::Sfz => {voice.set_sus_points(start_point, end_point, voice.internal_buffer.capacity());}
+                SamplerMode::Sfz | SamplerMode::Sf2 => {}
             }
         }
     }
@@ -403,9 +777,9 @@ impl SamplerEngine{
     /// Returns tuple in the format: (start_point, end_point)
     pub fn get_sus_points_warp(&mut self)->(f32,f32){
         match self.sampler_mode {
-            SamplerMode::Warp => {self.warp_voices[0].get_sus_points(self.warp_buffer.capacity())},
+            SamplerMode::Warp => {let capacity = self.warp_capacity(); self.warp_voices[0].get_sus_points(capacity)},
             SamplerMode::Assign => {(0.0,0.0)},
-            SamplerMode::Sfz => {(0.0,0.0)}
+            SamplerMode::Sfz | SamplerMode::Sf2 => {(0.0,0.0)}
         }
     }
     /// Sets the start and end points of the assigned buffer's sustain looping. Values will be clamped
@@ -414,9 +788,6 @@ impl SamplerEngine{
         if let Some((_file_name, _sr_scalar, buff, voice)) = self.sound_bank.get_mut(&note_of_assigned) {
             // Entry exists, update the points
             voice.set_sus_points(start_point, end_point, buff.capacity());
-        } else {
-            // Entry does not exist, handle the error (e.g., log an error message)
-            eprintln!("Entry for note {} does not exist in sound bank", note_of_assigned);
         }
     }
     /// Gets the start and end points for the sustain loop of the assigned note.
@@ -432,6 +803,9 @@ impl SamplerEngine{
     }
     /// Sets the sustain looping mode for the warping sampler
     pub fn set_sus_looping_warp(&mut self, mode: SustainModes){
+        if self.plays_regions(){
+            return; // Sfz regions choose their own loop mode
+        }
         for voice in self.warp_voices.iter_mut(){
             voice.set_sus_loop_mode(mode);
         }
@@ -441,9 +815,6 @@ impl SamplerEngine{
         if let Some((_file_name, _sr_scalar, _buff, voice)) = self.sound_bank.get_mut(&note_of_assigned) {
             // Entry exists, update the points
             voice.set_sus_loop_mode(mode);
-        } else {
-            // Entry does not exist, handle the error (e.g., log an error message)
-            eprintln!("Entry for note {} does not exist in sound bank", note_of_assigned);
         }
     }
     /// Sets crossfade time in seconds for the warp sampler, expects values between (0.00001 and 0.1)
@@ -452,16 +823,38 @@ impl SamplerEngine{
             voice.set_fade_time(fade_time);
         }
     }
+    /// Sets how every voice, in every mode, reads between the frames of its sample
+    pub fn set_interpolation(&mut self, mode: Interpolation){
+        for voice in self.warp_voices.iter_mut(){
+            voice.set_interpolation(mode);
+        }
+        for (_note, (_name,_sr_scalar,_buff,voice)) in self.sound_bank.iter_mut(){
+            voice.set_interpolation(mode);
+        }
+    }
     /// Sets crossfade time in seconds for the selected file, expects values between (0.00001 and 0.1)
     pub fn set_fade_time_assign(&mut self, fade_time: f32, note_of_assigned: u8){
         if let Some((_file_name, _sr_scalar, _buff, voice)) = self.sound_bank.get_mut(&note_of_assigned) {
             // Entry exists, update the points
             voice.set_fade_time(fade_time);
-        } else {
-            // Entry does not exist, handle the error (e.g., log an error message)
-            eprintln!("Entry for note {} does not exist in sound bank", note_of_assigned);
         }
     }
+    /// Returns whether the current mode plays the regions of an sfz instrument or SoundFont preset
+    fn plays_regions(&self)->bool{
+        matches!(self.sampler_mode, SamplerMode::Sfz | SamplerMode::Sf2)
+    }
+    /// Returns the length of the warp buffer in frames
+    fn warp_capacity(&self)->usize{
+        self.warp_buffer.as_ref().map_or(1, |buffer| buffer.capacity())
+    }
+    /// Returns a pseudo random number from 0 to 1 (exclusive) for picking random layers
+    fn next_random(&mut self)->f32{
+        // xorshift32
+        self.random_state ^= self.random_state << 13;
+        self.random_state ^= self.random_state >> 17;
+        self.random_state ^= self.random_state << 5;
+        (self.random_state >> 8) as f32 / (1 << 24) as f32
+    }
     /// Chooses a voice and steals the quietest one
     fn get_voice_id(&mut self)-> usize{
         for (voice_id, voice) in self.warp_voices.iter_mut().enumerate() {
@@ -501,108 +894,259 @@ impl SamplerEngine{
 
 }
 
-/// Fills a buffer with a file from a path
-fn fill_warp_buffer(buffer: &mut RingBuffer<f32>, path: &str) ->f32{
-    if let Ok(mut reader) = hound::WavReader::open(path){
-        let sample_format = reader.spec().sample_format;
-    let sample_rate = reader.spec().sample_rate as f32;
-    let length = reader.len();
-    buffer.resize(length as usize, 0.0);
-    buffer.set_write_index(0);
-    // Determine the conversion factor based on sample format
-    let conversion_factor = match sample_format {
-        SampleFormat::Float => 1.0, // No conversion needed
-        SampleFormat::Int => {
-            match reader.spec().bits_per_sample {
-                8 => 1.0 / (i8::MAX as f32),
-                16 => 1.0 / (i16::MAX as f32),
-                24 => 1.0 / (8388608 as f32), 
-                _ => panic!("Unsupported bit depth"),
-            }
+/// Sets the level, pan, pitch and filter of a voice from its sfz region and the current CC values
+fn apply_region_modulation(voice: &mut SamplerVoice, region: &SfzRegion, region_note: RegionNote, cc_values: &[f32; 128]){
+    let gain = region.gain(region_note.velocity) * region.cc_gain(cc_values) * region_note.attenuation;
+    voice.set_gain_and_pan(gain, region.cc_pan(cc_values));
+    let pitch = region.pitch_offset(region_note.velocity) + region.cc_pitch(cc_values);
+    voice.set_pitch(region.pitch_keytrack, pitch);
+    voice.set_filter(region.cc_cutoff(cc_values), region.resonance);
+}
+
+/// Reads an audio file into a buffer with one channel per channel in the file
+pub fn create_buffer(path: &str)->Result<(SampleBuffer,f32), DecodeError>{
+    decoder::decode_file(path).map(buffer_from_decoded)
+}
+
+/// Reads the bytes of an audio file, such as one packed inside an instrument archive.
+/// `name` is the file's name, which picks the decoder
+pub fn create_buffer_from_bytes(data: &[u8], name: &str)->Result<(SampleBuffer,f32), DecodeError>{
+    decoder::decode_bytes(data, name).map(buffer_from_decoded)
+}
+
+fn buffer_from_decoded(audio: DecodedAudio)-> (SampleBuffer,f32){
+    let buffer = SampleBuffer::from_interleaved(&audio.samples, audio.num_channels, audio.sample_rate);
+    (buffer.with_metadata(audio.metadata), audio.sample_rate)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use sfz::SfzTrigger;
+    use sample_buffer::SampleMetadata;
+    fn params()->EngineParams{
+        EngineParams{
+            attack: 0.0, decay: 0.0, sustain: 1.0, release: 0.1,
+            start_point: 0.0, end_point: 100.0, sus_start: 40.0, sus_end: 60.0,
+            sus_mode: SustainModes::NoLoop, fade_time: 0.0, num_voices: 6,
+            interpolation: Interpolation::Linear,
         }
-    };
-    match sample_format{
-        SampleFormat::Float => {
-            let mut samples = reader.samples::<f32>();
-            for _ in 0..(length) {
-                if let Some(sample) = samples.next() {
-                    if let Ok(sample_value) = sample {
-                        let sample_float = sample_value * conversion_factor;
-                         buffer.push(sample_float);
-                    }
-                }
-            }
-        }, 
-        SampleFormat::Int => {
-            let mut samples = reader.samples::<i32>();
-            for _ in 0..(length) {
-                
-                if let Some(sample) = samples.next() {
-                    if let Ok(sample_value) = sample {
-                        let sample_float = (sample_value as f32) * conversion_factor;
-                        buffer.push(sample_float);
-                    }
-                }
-            }
+    }
+    /// Swaps in an instrument and renders until the swap has faded back in
+    fn install(engine: &mut SamplerEngine, instrument: LoadedInstrument){
+        engine.swap_instrument(instrument);
+        let mut frame = [0.0; 2];
+        for _ in 0..2000{
+            engine.process(&mut frame);
         }
     }
-    sample_rate as f32
-    }else {
-        dbg!("File Failed to load");
-        44100.0
+    /// An engine playing an instrument of `regions` that all share one long sample
+    fn sfz_engine(regions: Vec<SfzRegion>)->SamplerEngine{
+        let mut engine = SamplerEngine::new(48000.0, 2);
+        engine.set_params(params());
+        let sample = Arc::new(SampleBuffer::from_interleaved(&[0.5; 48000], 1, 48000.0));
+        install(&mut engine, LoadedInstrument::Sfz(Arc::new(SfzInstrument::from_regions(regions, vec![sample]))));
+        engine
     }
-    
-}
-
-fn create_buffer(path: &str)-> (RingBuffer<f32>,f32){
-    if let Ok(mut reader) = hound::WavReader::open(path){
-        let sample_format = reader.spec().sample_format;
-    let num_channels = reader.spec().channels as usize;
-    let sample_rate = reader.spec().sample_rate as f32;
-    let length = reader.len();
-    let mut buffer = RingBuffer::<f32>::new(length as usize);
-    buffer.set_write_index(0);
-    // Determine the conversion factor based on sample format
-    let conversion_factor = match sample_format {
-        SampleFormat::Float => 1.0, // No conversion needed
-        SampleFormat::Int => {
-            match reader.spec().bits_per_sample {
-                8 => 1.0 / (i8::MAX as f32),
-                16 => 1.0 / (i16::MAX as f32),
-                24 => 1.0 / (8388608 as f32), 
-                _ => panic!("Unsupported bit depth"),
-            }
+    /// The envelope stage of the voice playing `note`
+    fn note_state(engine: &SamplerEngine, note: u8)->AdsrState{
+        engine.warp_voices.iter().find(|voice| voice.midi_note == note).unwrap().adsr.state.clone()
+    }
+    fn render_frames(engine: &mut SamplerEngine, frames: usize){
+        let mut frame = [0.0; 2];
+        for _ in 0..frames{
+            engine.process(&mut frame);
         }
-    };
-    match sample_format{
-        SampleFormat::Float => {
-            let mut samples = reader.samples::<f32>();
-            for _ in 0..(length) {
-                if let Some(sample) = samples.next() {
-                    if let Ok(sample_value) = sample {
-                        let sample_float = sample_value * conversion_factor;
-                         buffer.push(sample_float);
-                    }
-                }
-            }
-        }, 
-        SampleFormat::Int => {
-            let mut samples = reader.samples::<i32>();
-            for _ in 0..(length) {
-                
-                if let Some(sample) = samples.next() {
-                    if let Ok(sample_value) = sample {
-                        let sample_float = (sample_value as f32) * conversion_factor;
-                        buffer.push(sample_float);
-                    }
-                }
-            }
+    }
+    fn assert_close(rendered: &[f32], expected: &[f32]){
+        assert_eq!(rendered.len(), expected.len());
+        for (rendered, expected) in rendered.iter().zip(expected){
+            assert!((rendered - expected).abs() < 1e-5, "{:?} {:?}", rendered, expected);
+        }
+    }
+    #[test]
+    fn test_keyswitch_is_not_legato(){
+        let mut engine = SamplerEngine::new(48000.0, 2);
+        engine.set_params(params());
+        let sample = Arc::new(SampleBuffer::from_interleaved(&[0.5; 1000], 1, 48000.0));
+        let first = SfzRegion{lokey: 60, hikey: 60, trigger: SfzTrigger::First, sw_down: Some(36), ..SfzRegion::default()};
+        let legato = SfzRegion{lokey: 62, hikey: 62, trigger: SfzTrigger::Legato, sw_down: Some(36), ..SfzRegion::default()};
+        install(&mut engine, LoadedInstrument::Sfz(Arc::new(SfzInstrument::from_regions(vec![first, legato], vec![sample]))));
+        let mut frame = [0.0; 2];
+        engine.note_on(36, 1.0);
+        engine.note_on(62, 1.0);
+        engine.process(&mut frame);
+        assert_eq!(frame[0], 0.0);
+        engine.note_off(62);
+        for _ in 0..48000{
+            engine.process(&mut frame);
+        }
+        engine.note_on(60, 1.0);
+        engine.process(&mut frame);
+        assert!(frame[0] > 0.0);
+    }
+    #[test]
+    fn test_file_loop_survives_point_changes(){
+        let mut engine = SamplerEngine::new(48000.0, 2);
+        engine.set_params(params());
+        let metadata = SampleMetadata{sustain_loop: Some(SampleLoop{start: 200, end: 300, bounce: true}), ..SampleMetadata::default()};
+        let sample = SampleBuffer::from_interleaved(&[0.5; 1000], 1, 48000.0).with_metadata(metadata);
+        install(&mut engine, LoadedInstrument::Warp(Arc::new(sample)));
+        assert!(engine.uses_file_loop());
+        assert_eq!(engine.get_sus_points_warp(), (20.0, 30.0));
+        engine.set_params(EngineParams{start_point: 10.0, end_point: 90.0, ..params()});
+        assert!(engine.uses_file_loop());
+        assert_eq!(engine.get_sus_points_warp(), (20.0, 30.0));
+        // Moving a sustain point hands the loop back to the params
+        engine.set_params(EngineParams{start_point: 10.0, end_point: 90.0, sus_start: 50.0, ..params()});
+        assert!(!engine.uses_file_loop());
+        let (sus_start, sus_end) = engine.get_sus_points_warp();
+        assert!((sus_start - 50.0).abs() < 1e-3 && (sus_end - 60.0).abs() < 1e-3, "{} {}", sus_start, sus_end);
+    }
+    #[test]
+    fn test_set_params_applies_changes_only(){
+        let mut engine = SamplerEngine::new(48000.0, 2);
+        engine.set_params(params());
+        install(&mut engine, LoadedInstrument::Warp(Arc::new(SampleBuffer::from_interleaved(&[0.5; 1000], 1, 48000.0))));
+        // Moved behind the engine's back, so passing the points on again would undo it
+        let capacity = engine.warp_capacity();
+        for voice in engine.warp_voices.iter_mut(){
+            voice.set_start_and_end_point(25.0, 75.0, capacity);
+        }
+        let moved = engine.get_points_warp();
+        engine.set_params(EngineParams{attack: 0.5, ..params()});
+        for voice in engine.warp_voices.iter_mut(){
+            assert_eq!(voice.adsr.get_adsr().0, 0.5);
+            assert_eq!(voice.get_points(capacity), moved);
+        }
+        engine.set_params(EngineParams{attack: 0.5, end_point: 50.0, ..params()});
+        assert_ne!(engine.get_points_warp(), moved);
+    }
+    #[test]
+    fn test_file_loop_returns_with_the_next_sample(){
+        let mut engine = SamplerEngine::new(48000.0, 2);
+        engine.set_params(params());
+        let metadata = SampleMetadata{sustain_loop: Some(SampleLoop{start: 200, end: 300, bounce: false}), ..SampleMetadata::default()};
+        let sample = Arc::new(SampleBuffer::from_interleaved(&[0.5; 1000], 1, 48000.0).with_metadata(metadata));
+        install(&mut engine, LoadedInstrument::Warp(sample.clone()));
+        engine.set_params(EngineParams{sus_mode: SustainModes::LoopWrap, ..params()});
+        assert!(!engine.uses_file_loop());
+        // Params that don't touch the loop leave the user's loop alone
+        engine.set_params(EngineParams{sus_mode: SustainModes::LoopWrap, attack: 0.5, ..params()});
+        assert!(!engine.uses_file_loop());
+        install(&mut engine, LoadedInstrument::Warp(sample));
+        assert!(engine.uses_file_loop());
+        assert_eq!(engine.get_sus_points_warp(), (20.0, 30.0));
+    }
+    #[test]
+    fn test_render(){
+        let mut engine = SamplerEngine::new(48000.0, 2);
+        engine.set_params(params());
+        let ramp = (0..1000).map(|i| i as f32 / 1000.0).collect::<Vec<_>>();
+        install(&mut engine, LoadedInstrument::Warp(Arc::new(SampleBuffer::from_interleaved(&ramp, 1, 48000.0))));
+        let (mut left, mut right) = ([1.0; 16], [1.0; 16]);
+        engine.render(&mut [&mut left, &mut right], &[EngineEvent::NoteOn{timing: 0, note: 60, velocity: 1.0}]);
+        // The base note plays the sample unchanged, and a mono sample feeds both channels
+        assert_close(&left, &ramp[..16]);
+        assert_close(&right, &ramp[..16]);
+        engine.render(&mut [&mut left, &mut right], &[]);
+        assert_close(&left, &ramp[16..32]);
+    }
+    #[test]
+    fn test_render_event_offsets(){
+        let mut engine = SamplerEngine::new(48000.0, 2);
+        engine.set_params(params());
+        install(&mut engine, LoadedInstrument::Warp(Arc::new(SampleBuffer::from_interleaved(&[0.5; 1000], 1, 48000.0))));
+        let (mut left, mut right) = ([0.0; 64], [0.0; 64]);
+        engine.render(&mut [&mut left, &mut right], &[EngineEvent::NoteOn{timing: 20, note: 60, velocity: 1.0}]);
+        assert!(left[..20].iter().all(|sample| *sample == 0.0));
+        assert!(left[20..].iter().all(|sample| (sample - 0.5).abs() < 1e-5));
+        engine.render(&mut [&mut left, &mut right], &[EngineEvent::NoteOff{timing: 10, note: 60}]);
+        assert!((left[9] - 0.5).abs() < 1e-5);
+        assert!(left[10] < 0.5);
+    }
+    #[test]
+    fn test_render_applies_late_events_at_the_end(){
+        let mut engine = SamplerEngine::new(48000.0, 2);
+        engine.set_params(params());
+        install(&mut engine, LoadedInstrument::Warp(Arc::new(SampleBuffer::from_interleaved(&[0.5; 1000], 1, 48000.0))));
+        let (mut left, mut right) = ([0.0; 64], [0.0; 64]);
+        engine.render(&mut [&mut left, &mut right], &[EngineEvent::NoteOn{timing: 100, note: 60, velocity: 1.0}]);
+        assert!(left.iter().all(|sample| *sample == 0.0));
+        // The note wasn't dropped, so it sounds from the start of the next block
+        engine.render(&mut [&mut left, &mut right], &[]);
+        assert!(left.iter().all(|sample| (sample - 0.5).abs() < 1e-5));
+    }
+    #[test]
+    fn test_first_swap_frees_nothing(){
+        let mut engine = SamplerEngine::new(48000.0, 2);
+        assert!(engine.instrument.is_none());
+        let sample = Arc::new(SampleBuffer::from_interleaved(&[0.5; 1000], 1, 48000.0));
+        let instrument = Arc::new(SfzInstrument::from_regions(vec![SfzRegion::default()], vec![sample]));
+        install(&mut engine, LoadedInstrument::Sfz(instrument.clone()));
+        // The engine took the instrument without replacing, and so freeing, anything
+        assert!(engine.instrument.as_ref().is_some_and(|installed| Arc::ptr_eq(installed, &instrument)));
+        assert!(engine.pending.is_none());
+        assert_eq!(Arc::strong_count(&instrument), 2);
+    }
+    #[test]
+    fn test_off_by(){
+        for (off_mode, stopped) in [(SfzOffMode::Fast, true), (SfzOffMode::Normal, false)]{
+            let open = SfzRegion{lokey: 46, hikey: 46, group: 1, off_by: Some(2), off_mode, ampeg_release: Some(1.0), ..SfzRegion::default()};
+            let closed = SfzRegion{lokey: 42, hikey: 42, group: 2, ..SfzRegion::default()};
+            let mut engine = sfz_engine(vec![open, closed]);
+            engine.note_on(46, 1.0);
+            render_frames(&mut engine, 10);
+            engine.note_on(42, 1.0);
+            assert_eq!(note_state(&engine, 46), AdsrState::Release);
+            assert_ne!(note_state(&engine, 42), AdsrState::Release);
+            // A fast stop is over in a few milliseconds, a normal one takes the region's release
+            render_frames(&mut engine, 480);
+            assert_eq!(note_state(&engine, 46) == AdsrState::Inactive, stopped, "{:?}", off_mode);
         }
     }
-    (buffer,sample_rate)
-    }else{
-        dbg!("File Failed to load");
-        (RingBuffer::new(1), 44100.0)
+    #[test]
+    fn test_group_polyphony(){
+        // Each new note is quieter than the last while they fade in, so the quietest isn't the oldest
+        let region = SfzRegion{lokey: 60, hikey: 64, group: 3, polyphony: Some(2), ampeg_attack: Some(0.1), ..SfzRegion::default()};
+        let mut engine = sfz_engine(vec![region]);
+        for note in 60..=61{
+            engine.note_on(note, 1.0);
+            render_frames(&mut engine, 100);
+        }
+        engine.note_on(62, 1.0);
+        assert_eq!(note_state(&engine, 60), AdsrState::Release);
+        assert_eq!(note_state(&engine, 61), AdsrState::Attack);
+        assert_eq!(note_state(&engine, 62), AdsrState::Attack);
+        engine.note_on(63, 1.0);
+        assert_eq!(note_state(&engine, 61), AdsrState::Release);
+        assert_eq!(note_state(&engine, 62), AdsrState::Attack);
+    }
+    #[test]
+    fn test_zero_group_polyphony(){
+        let region = SfzRegion{lokey: 60, hikey: 64, group: 3, polyphony: Some(0), ..SfzRegion::default()};
+        let mut engine = sfz_engine(vec![region]);
+        engine.note_on(60, 1.0);
+        render_frames(&mut engine, 10);
+        engine.note_on(61, 1.0);
+        // The new note still plays, it only stops the ones before it
+        assert_eq!(note_state(&engine, 60), AdsrState::Release);
+        assert_ne!(note_state(&engine, 61), AdsrState::Release);
+        let mut frame = [0.0; 2];
+        engine.process(&mut frame);
+        assert!(frame[0] > 0.0);
     }
-    
-}
\ No newline at end of file
+    #[test]
+    fn test_region_pitch(){
+        let region = SfzRegion{pitch_keycenter: 60, transpose: 12.0, tune: 50.0, pitch_keytrack: 0.0, pitch_veltrack: 600.0, ..SfzRegion::default()};
+        for (note, velocity) in [(60, 1.0), (30, 1.0), (90, 64.0 / 127.0)]{
+            let mut engine = sfz_engine(vec![region.clone()]);
+            engine.note_on(note, velocity);
+            let voice = engine.warp_voices.iter().find(|voice| voice.midi_note == note).unwrap();
+            // The key doesn't move the pitch, only the tuning, the transposition and the velocity do
+            let cents = 50.0 + 1200.0 + 600.0 * (velocity * 127.0).round() / 127.0;
+            let expected = 2.0_f32.powf(cents / 1200.0);
+            assert!((voice.phase_step() - expected).abs() < 1e-4, "{} {} {}", note, voice.phase_step(), expected);
+        }
+    }
+}
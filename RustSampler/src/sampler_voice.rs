@@ -1,12 +1,20 @@
 use std::clone;
 use std::fmt;
-use crate::ring_buffer;
 use nih_plug::params::enums::Enum;
-use ring_buffer::RingBuffer;
 use crate::adsr;
 use adsr::{Adsr, AdsrState};
 use crate::crossfade;
 use crossfade::Crossfade;
+use crate::sample_buffer;
+use sample_buffer::{SampleBuffer, SampleLoop};
+use crate::filter;
+use filter::LowpassFilter;
+use crate::interpolation;
+use interpolation::Interpolation;
+use std::sync::Arc;
+
+/// Release time in seconds of a voice that is stopped quickly by `choke`
+const FAST_OFF_TIME: f32 = 0.006;
 
 #[derive(Clone)]
 pub struct SamplerVoice{
@@ -28,7 +36,36 @@ pub struct SamplerVoice{
     fade_time: f32,
     sus_passed: bool,
     voice_type: VoiceType,
-    pub internal_buffer: RingBuffer<f32>
+    loop_while: LoopWhile,
+    one_shot: bool,
+    channel_gains: [f32; 2],
+    keytrack: f32,
+    pitch_offset: f32,
+    pub internal_buffer: Option<Arc<SampleBuffer>>,
+    /// Exclusive group of the sfz region being played
+    pub group: i32,
+    /// Group whose regions stop this voice when they start
+    pub off_by: Option<i32>,
+    /// Whether the voice fades out quickly instead of releasing when it's stopped by its off_by group
+    pub fast_off: bool,
+    /// The sfz region the voice is playing, so CC changes can update it
+    pub region: Option<RegionNote>,
+    /// The engine frame the note started on, so group polyphony can stop the oldest voice
+    pub start_frame: u64,
+    filter: LowpassFilter,
+    filter_on: bool,
+    /// How the voice reads between the frames of its sample
+    interpolation: Interpolation,
+}
+/// The sfz region a voice was started with and the values it was started with
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RegionNote{
+    /// Index of the region in the instrument
+    pub id: usize,
+    /// Note on velocity (0-127)
+    pub velocity: u8,
+    /// Extra linear gain, such as crossfades and the rt_decay of release regions
+    pub attenuation: f32,
 }
 #[derive(Clone, Copy, PartialEq, Enum, Debug)]
 pub enum SustainModes {
@@ -41,6 +78,13 @@ impl SustainModes {
         [SustainModes::NoLoop, SustainModes::LoopWrap, SustainModes::LoopBounce].iter().copied()
     }
 }
+/// Which stages of the envelope the sustain loop runs in
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LoopWhile{
+    SustainStage, // Only once the ADSR has reached its sustain stage
+    KeyHeld, // From the start of the note until it is released
+    Always, // For as long as the voice is playing, including the release
+}
 #[derive(Clone, Copy, PartialEq)]
 pub enum VoiceType{
     Warp,
@@ -73,92 +117,107 @@ impl SamplerVoice{
             fade_time: 0.0002,
             sus_passed: false,
             voice_type: voice_type_,
-            internal_buffer: RingBuffer::<f32>::new(1)
+            loop_while: LoopWhile::SustainStage,
+            one_shot: false,
+            channel_gains: [1.0, 1.0],
+            keytrack: 100.0,
+            pitch_offset: 0.0,
+            internal_buffer: None,
+            group: 0,
+            off_by: None,
+            fast_off: true,
+            region: None,
+            start_frame: 0,
+            filter: LowpassFilter::new(sample_rate_),
+            filter_on: false,
+            interpolation: Interpolation::Linear,
         }
     }
-    ///Reads from the loaded sample file
-    /// Uses the get_frac function in the ring_buffer, which returns the sample
-    /// at a fractional index
-    pub fn process(&mut self, buffer: &mut RingBuffer<f32>, sr_scalar: f32)->f32{
-        self.check_inits(buffer.capacity());
-        let fade_samps = self.fade_time*self.sample_rate;
-        let cross_start;
-        if self.adsr.is_active(){
-            let mut sample = buffer.get_frac(self.phase_offset);
-            if !self.reversed{
-                cross_start = self.sus_end - fade_samps;
-                self.phase_offset += self.phase_step * sr_scalar;
-                if self.sus_mode != SustainModes::NoLoop{
-                    self.sus_logic(&mut sample, cross_start);
-                }
-                if self.phase_offset >= self.end_point{
-                    self.phase_step = 0.0;
-                    self.phase_offset = self.start_point;
-                    return 0.0
-                }
-            }else{     
-                cross_start = self.sus_start + fade_samps;
-                self.phase_offset -= self.phase_step * sr_scalar;
-                if self.sus_mode != SustainModes::NoLoop{
-                    self.sus_logic(&mut sample, cross_start);
-                }
-                if self.phase_offset <= self.end_point{
-                    self.phase_step = 0.0;
-                    self.phase_offset = self.start_point;
-                    return 0.0
+    /// Reads one frame of the sample with the voice's interpolation mode and adds it to `frame`
+    /// 
+    /// Each output channel reads from the matching channel of the sample, so the voice only
+    /// advances once per frame
+    pub fn process(&mut self, buffer: &SampleBuffer, sr_scalar: f32, frame: &mut [f32]){
+        if let Some((read_point, gain)) = self.next_read_point(buffer.capacity(), sr_scalar){
+            let step = self.phase_step * sr_scalar;
+            let sample_loop = self.active_loop(read_point);
+            for (channel, out) in frame.iter_mut().enumerate(){
+                let channel_gain = self.channel_gains[channel.min(1)];
+                let mut sample = buffer.get_interpolated(channel, read_point, self.interpolation, step, sample_loop) * gain * channel_gain;
+                if self.filter_on{
+                    sample = self.filter.process(channel, sample);
                 }
+                *out += sample;
             }
-            sample * self.adsr.get_next_sample()
-        }else{
-            self.phase_offset = self.start_point;
-            self.sus_passed = false;
-            0.0
         }
     }
-    pub fn process_sfz(&mut self, sr_scalar:f32)->f32{
-        self.check_inits(self.internal_buffer.capacity());
+    /// Same as process, but reads from the voice's internal buffer at the buffer's own sample rate
+    pub fn process_sfz(&mut self, frame: &mut [f32]){
+        // Taken out for the call so the voice can read its own buffer while advancing
+        if let Some(buffer) = self.internal_buffer.take(){
+            let sr_scalar = buffer.sample_rate() / self.sample_rate;
+            self.process(&buffer, sr_scalar, frame);
+            self.internal_buffer = Some(buffer);
+        }
+    }
+    /// Returns the length in frames of the internal buffer
+    pub fn internal_capacity(&self)->usize{
+        self.internal_buffer.as_ref().map_or(1, |buffer| buffer.capacity())
+    }
+    /// Advances the playback position by one frame
+    /// 
+    /// Returns the position to read from and the gain to apply to it, 
+    /// or None if the voice is silent
+    fn next_read_point(&mut self, capacity: usize, sr_scalar: f32)->Option<(f32, f32)>{
+        self.check_inits(capacity);
         let fade_samps = self.fade_time*self.sample_rate;
         let cross_start;
         if self.adsr.is_active(){
-            let mut sample = self.internal_buffer.get_frac(self.phase_offset);
+            let read_point = self.phase_offset;
+            let mut gain = 1.0;
             if !self.reversed{
                 cross_start = self.sus_end - fade_samps;
                 self.phase_offset += self.phase_step * sr_scalar;
                 if self.sus_mode != SustainModes::NoLoop{
-                    self.sus_logic(&mut sample, cross_start);
+                    self.sus_logic(&mut gain, cross_start);
                 }
                 if self.phase_offset >= self.end_point{
+                    // The sample has run out, so the voice is free again
                     self.phase_step = 0.0;
                     self.phase_offset = self.start_point;
-                    return 0.0
+                    self.adsr.reset();
+                    return None
                 }
             }else{     
                 cross_start = self.sus_start + fade_samps;
                 self.phase_offset -= self.phase_step * sr_scalar;
                 if self.sus_mode != SustainModes::NoLoop{
-                    self.sus_logic(&mut sample, cross_start);
+                    self.sus_logic(&mut gain, cross_start);
                 }
                 if self.phase_offset <= self.end_point{
                     self.phase_step = 0.0;
                     self.phase_offset = self.start_point;
-                    return 0.0
+                    self.adsr.reset();
+                    return None
                 }
             }
-            sample * self.adsr.get_next_sample()
+            Some((read_point, gain * self.adsr.get_next_sample()))
         }else{
             self.phase_offset = self.start_point;
             self.sus_passed = false;
-            0.0
+            None
         }
     }
     ///Sets the midi note for the output
     /// 
-    /// Is in reference to the base midi note
+    /// Is in reference to the base midi note, scaled by the keytracking and
+    /// shifted by the pitch offset
     pub fn set_note(&mut self, note: u8){
         self.midi_note = note;
         if self.voice_type == VoiceType::Warp{
             let offset = iclamp((note as i8 - self.base_midi as i8)as i32,-127,127);
-            self.phase_step = 2.0_f32.powf(offset as f32 / 12.0);
+            let cents = offset as f32 * self.keytrack + self.pitch_offset;
+            self.phase_step = 2.0_f32.powf(cents / 1200.0);
         }else{
             self.phase_step = 1.0;
         }
@@ -170,11 +229,22 @@ impl SamplerVoice{
         }
         self.phase_offset = self.start_point;
         self.set_note(note);
+        self.filter.reset();
         self.adsr.note_on();
     }
-    /// Triggers release on ADSR
+    /// Triggers release on ADSR. One shot voices ignore it and play to the end of the sample
     pub fn note_off(&mut self){
-        self.adsr.note_off()
+        if !self.one_shot{
+            self.adsr.note_off()
+        }
+    }
+    /// Stops the voice, including one shots, either with its release or with a quick fade out
+    pub fn choke(&mut self, fast: bool){
+        if fast{
+            self.adsr.quick_release(FAST_OFF_TIME);
+        }else{
+            self.adsr.note_off();
+        }
     }
     /// Sets the attack, decay, sustain, and release for the ADSR (in seconds)
     pub fn set_adsr(&mut self, attack_:f32, decay_:f32, sustain_:f32, release_:f32){
@@ -212,6 +282,61 @@ impl SamplerVoice{
         self.set_start_point(start_point,length);
         self.set_end_point(end_point,length);
     }
+    /// Sets the start, end and sustain loop points directly in frames, for playback ranges
+    /// that come from the instrument file rather than the gui. Playback is always forwards
+    pub fn set_points_in_frames(&mut self, start_point: f32, end_point: f32, sus_start: f32, sus_end: f32){
+        self.start_point = start_point;
+        self.end_point = end_point;
+        self.sus_start = sus_start;
+        self.sus_end = sus_end;
+        self.reversed = false;
+    }
+    /// Sets the sustain loop points directly in frames, such as a loop stored in the sample's file
+    pub fn set_sus_points_in_frames(&mut self, sus_start: f32, sus_end: f32){
+        self.sus_start = sus_start;
+        self.sus_end = sus_end;
+    }
+    /// Sets when the sustain loop runs and whether note offs are ignored
+    pub fn set_loop_behaviour(&mut self, loop_while: LoopWhile, one_shot: bool){
+        self.loop_while = loop_while;
+        self.one_shot = one_shot;
+    }
+    /// Sets the level (linear gain) and pan (-100 left to 100 right) of the voice.
+    /// 
+    /// Uses an equal power pan law that leaves the center at unity gain
+    pub fn set_gain_and_pan(&mut self, gain: f32, pan: f32){
+        let angle = (fclamp(pan, -100.0, 100.0) + 100.0) / 200.0 * std::f32::consts::FRAC_PI_2;
+        let law = std::f32::consts::SQRT_2;
+        self.channel_gains = [gain * angle.cos() * law, gain * angle.sin() * law];
+    }
+    /// Sets how many cents the pitch moves per key away from the base note (100 by default,
+    /// 0 plays every key at the same pitch) and a fixed offset in cents
+    pub fn set_pitch(&mut self, keytrack: f32, offset_cents: f32){
+        self.keytrack = keytrack;
+        self.pitch_offset = offset_cents;
+        // Lets the pitch move while the note plays
+        self.set_note(self.midi_note);
+    }
+    /// Sets the lowpass cutoff in Hz and resonance in dB. A cutoff of None turns the filter off
+    pub fn set_filter(&mut self, cutoff: Option<f32>, resonance: f32){
+        self.filter_on = cutoff.is_some();
+        if let Some(cutoff) = cutoff{
+            self.filter.set_cutoff(cutoff, resonance);
+        }
+    }
+    /// Removes the loop, level, pitch and envelope settings that an instrument region gave the voice
+    pub fn clear_region_settings(&mut self){
+        self.set_loop_behaviour(LoopWhile::SustainStage, false);
+        self.set_gain_and_pan(1.0, 0.0);
+        self.set_pitch(100.0, 0.0);
+        self.adsr.set_delay(0.0);
+        self.adsr.set_hold(0.0);
+        self.group = 0;
+        self.off_by = None;
+        self.fast_off = true;
+        self.region = None;
+        self.set_filter(None, 0.0);
+    }
     /// Sets the start point of the sustain loop. If reversed, start_point will serve
     /// as end_point. Values will be clamped within start and end points of the 
     /// sample as a whole.
@@ -256,6 +381,9 @@ impl SamplerVoice{
     /// 
     /// start_point: (0%-100%),  end_point: (0%-100%)
     pub fn set_sus_points(&mut self, start_point: f32, end_point: f32, length: usize){
+        self.check_inits(length);
+        // Open the loop up first, so the new start isn't clamped to an old end such as a file's loop
+        self.sus_end = if self.reversed {self.start_point} else {self.end_point};
         self.set_sus_start(start_point, length);
         self.set_sus_end(end_point, length);
     }
@@ -281,10 +409,18 @@ impl SamplerVoice{
     pub fn is_active(&mut self)->bool{
         self.adsr.is_active()
     }
+    /// Returns how many frames of the sample the voice moves on per output sample
+    pub fn phase_step(&self)->f32{
+        self.phase_step
+    }
     /// Sets center midi note upon which sample warping is wrapped
     pub fn set_base_midi(&mut self, note: u8){
         self.base_midi = note;
     }
+    /// Sets how the voice reads between the frames of its sample
+    pub fn set_interpolation(&mut self, mode: Interpolation){
+        self.interpolation = mode;
+    }
     /// Sets crossfade time in seconds, expects values between (0.00001 and 0.1)
     pub fn set_fade_time(&mut self, fade_time: f32){
         self.fade_time = fclamp(fade_time, 0.0, 0.1);
@@ -306,9 +442,30 @@ impl SamplerVoice{
             self.sus_end = 0.6 * capacity as f32;
         }
     }
+    /// Returns whether the envelope is in a stage the sustain loop runs in
+    fn loop_is_running(&self)->bool{
+        match self.loop_while{
+            LoopWhile::SustainStage => self.adsr.state == AdsrState::Sustain,
+            LoopWhile::KeyHeld => self.adsr.state != AdsrState::Release,
+            LoopWhile::Always => true,
+        }
+    }
+    /// Returns the sustain loop in whole frames while `read_point` is playing inside it,
+    /// so the interpolation reads across the loop's seam instead of past it
+    fn active_loop(&self, read_point: f32)->Option<SampleLoop>{
+        let inside = read_point >= self.sus_start && read_point <= self.sus_end;
+        if self.sus_mode == SustainModes::NoLoop || !inside || !self.loop_is_running(){
+            return None;
+        }
+        Some(SampleLoop{
+            start: self.sus_start.round() as usize,
+            end: self.sus_end.round() as usize,
+            bounce: self.sus_mode == SustainModes::LoopBounce,
+        })
+    }
     /// Handles the logic for the different sustain looping modes
-    fn sus_logic(&mut self, sample: &mut f32, cross_start: f32){
-        if self.adsr.state == AdsrState::Sustain{
+    fn sus_logic(&mut self, gain: &mut f32, cross_start: f32){
+        if self.loop_is_running(){
             if self.sus_mode == SustainModes::LoopWrap{
                  if !self.reversed{
                      if self.phase_offset >= cross_start && self.phase_offset <= cross_start+self.phase_step{
@@ -319,7 +476,7 @@ impl SamplerVoice{
                          self.crossfader.start_fade_in();
                      }
                      if self.phase_offset >= self.sus_start{
-                         *sample *= self.crossfader.get_next_sample();
+                         *gain *= self.crossfader.get_next_sample();
                      }
                  } else{
                      if self.phase_offset <= cross_start && self.phase_offset >= cross_start-self.phase_step{
@@ -330,7 +487,7 @@ impl SamplerVoice{
                          self.crossfader.start_fade_in();
                      }
                      if self.phase_offset <= self.sus_end{
-                         *sample *= self.crossfader.get_next_sample();
+                         *gain *= self.crossfader.get_next_sample();
                      }
                  }
             }else if self.sus_mode == SustainModes::LoopBounce {
@@ -385,3 +542,51 @@ fn iclamp(x: i32, min_val: i32, max_val: i32) -> i32 {
         x
     }
 }
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    #[test]
+    fn test_stereo_process(){
+        let interleaved = (0..100).flat_map(|i| [i as f32 / 100.0, -(i as f32) / 200.0]).collect::<Vec<_>>();
+        let buffer = SampleBuffer::from_interleaved(&interleaved, 2, 48000.0);
+        let mut voice = SamplerVoice::new(2, 48000.0, 60, VoiceType::Warp);
+        voice.set_adsr(0.0, 0.0, 1.0, 0.1);
+        voice.note_on(60, 1.0);
+        for i in 0..10{
+            let mut frame = [0.0; 2];
+            voice.process(&buffer, 1.0, &mut frame);
+            assert!((frame[0] - i as f32 / 100.0).abs() < 1e-6, "{} {:?}", i, frame);
+            assert!((frame[1] + i as f32 / 200.0).abs() < 1e-6, "{} {:?}", i, frame);
+        }
+        // One step per frame, not one per channel
+        assert_eq!(voice.phase_offset, 10.0);
+    }
+    #[test]
+    fn test_loop_seam(){
+        // Only the loop holds signal, so any read past its ends would pull in silence
+        let samples = (0..40).map(|i| if (10..20).contains(&i) {1.0} else {0.0}).collect::<Vec<_>>();
+        let buffer = SampleBuffer::from_interleaved(&samples, 1, 48000.0);
+        let mut voice = SamplerVoice::new(1, 48000.0, 60, VoiceType::Warp);
+        voice.set_points_in_frames(0.0, 40.0, 10.0, 20.0);
+        voice.set_sus_loop_mode(SustainModes::LoopWrap);
+        voice.set_loop_behaviour(LoopWhile::Always, false);
+        voice.note_on(60, 1.0);
+        let sample_loop = voice.active_loop(19.5);
+        assert_eq!(sample_loop, Some(SampleLoop{start: 10, end: 20, bounce: false}));
+        for mode in [Interpolation::Hermite, Interpolation::Lagrange4, Interpolation::Lagrange6, Interpolation::Sinc]{
+            assert!((buffer.get_interpolated(0, 19.5, mode, 1.0, sample_loop) - 1.0).abs() < 1e-2, "{:?}", mode);
+            assert!((buffer.get_interpolated(0, 10.5, mode, 1.0, sample_loop) - 1.0).abs() < 1e-2, "{:?}", mode);
+            assert!(buffer.get_interpolated(0, 19.5, mode, 1.0, None) < 0.9, "{:?}", mode);
+        }
+        // Past the loop, or without one, reads are left alone
+        assert_eq!(voice.active_loop(30.0), None);
+        voice.set_sus_loop_mode(SustainModes::NoLoop);
+        assert_eq!(voice.active_loop(19.5), None);
+        // A bouncing loop mirrors around its ends
+        let bounce = SampleLoop{start: 10, end: 20, bounce: true};
+        assert_eq!([bounce.fold(9), bounce.fold(15), bounce.fold(21), bounce.fold(31)], [11, 15, 19, 11]);
+        let wrap = SampleLoop{bounce: false, ..bounce};
+        assert_eq!([wrap.fold(9), wrap.fold(20), wrap.fold(21), wrap.fold(-1)], [19, 10, 11, 19]);
+    }
+}
@@ -0,0 +1,509 @@
+use crate::{sample_buffer,sfz,resampler};
+use sample_buffer::SampleBuffer;
+use sfz::{SfzInstrument, SfzLoopMode, SfzOffMode, SfzRegion};
+use resampler::{ResampleQuality, resample_shared};
+use std::{fs, io, path::Path, sync::Arc};
+
+// Generator numbers from the SoundFont 2.01 specification
+const START_OFFSET: u16 = 0;
+const END_OFFSET: u16 = 1;
+const LOOP_START_OFFSET: u16 = 2;
+const LOOP_END_OFFSET: u16 = 3;
+const START_COARSE_OFFSET: u16 = 4;
+const FILTER_CUTOFF: u16 = 8;
+const FILTER_Q: u16 = 9;
+const END_COARSE_OFFSET: u16 = 12;
+const PAN: u16 = 17;
+const DELAY_VOL_ENV: u16 = 33;
+const ATTACK_VOL_ENV: u16 = 34;
+const HOLD_VOL_ENV: u16 = 35;
+const DECAY_VOL_ENV: u16 = 36;
+const SUSTAIN_VOL_ENV: u16 = 37;
+const RELEASE_VOL_ENV: u16 = 38;
+const INSTRUMENT: u16 = 41;
+const KEY_RANGE: u16 = 43;
+const VEL_RANGE: u16 = 44;
+const LOOP_START_COARSE_OFFSET: u16 = 45;
+const INITIAL_ATTENUATION: u16 = 48;
+const LOOP_END_COARSE_OFFSET: u16 = 50;
+const COARSE_TUNE: u16 = 51;
+const FINE_TUNE: u16 = 52;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: u16 = 54;
+const SCALE_TUNING: u16 = 56;
+const EXCLUSIVE_CLASS: u16 = 57;
+const OVERRIDING_ROOT_KEY: u16 = 58;
+const NUM_GENERATORS: usize = 61;
+
+/// Cutoffs at or above this many absolute cents leave the filter open
+const FILTER_OFF_CENTS: i32 = 13500;
+
+/// A preset of a SoundFont, turned into the same regions an sfz file uses
+pub struct Sf2Preset{
+    pub name: String,
+    pub bank: u16,
+    pub program: u16,
+    pub instrument: Arc<SfzInstrument>,
+}
+
+/// The presets of a SoundFont. All the presets share one set of decoded samples
+pub struct Sf2Bank{
+    presets: Vec<Sf2Preset>,
+    samples: Vec<Arc<SampleBuffer>>,
+}
+
+/// The generators set on a zone, indexed by generator number
+#[derive(Clone, Copy)]
+struct Generators([Option<u16>; NUM_GENERATORS]);
+
+impl Generators{
+    fn new()->Self{
+        Generators([None; NUM_GENERATORS])
+    }
+    fn get(&self, generator: u16)->Option<u16>{
+        self.0.get(generator as usize).copied().flatten()
+    }
+    /// Returns a signed generator value, or `default` if the zone doesn't set it
+    fn signed(&self, generator: u16, default: i32)->i32{
+        self.get(generator).map_or(default, |amount| amount as i16 as i32)
+    }
+    /// Returns a key or velocity range as (low, high)
+    fn range(&self, generator: u16)->(u8, u8){
+        self.get(generator).map_or((0, 127), |amount| ((amount & 0xff) as u8, (amount >> 8) as u8))
+    }
+    /// Sets every generator of `zone` on top of these ones
+    fn overlay(&self, zone: &Generators)->Generators{
+        let mut merged = *self;
+        for (generator, amount) in zone.0.iter().enumerate(){
+            if amount.is_some(){
+                merged.0[generator] = *amount;
+            }
+        }
+        merged
+    }
+}
+
+/// A sample header (shdr) of the SoundFont
+struct SampleHeader{
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_rate: u32,
+    original_pitch: u8,
+    pitch_correction: i8,
+    sample_type: u16,
+}
+
+/// The hydra chunks of the pdta list, which describe the presets and instruments
+#[derive(Default)]
+struct Hydra<'a>{
+    phdr: &'a [u8],
+    pbag: &'a [u8],
+    pgen: &'a [u8],
+    inst: &'a [u8],
+    ibag: &'a [u8],
+    igen: &'a [u8],
+    shdr: &'a [u8],
+}
+
+impl Sf2Bank{
+    /// Reads a SoundFont file and decodes all of its samples
+    pub fn from_file(path: &Path)->io::Result<Self>{
+        Sf2Bank::from_bytes(&fs::read(path)?)
+    }
+    /// Reads a SoundFont from the bytes of a file
+    pub fn from_bytes(data: &[u8])->io::Result<Self>{
+        let body = match data.get(..12){
+            Some(header) if &header[..4] == b"RIFF" && &header[8..12] == b"sfbk" => &data[12..],
+            _ => return Err(invalid("not a SoundFont file")),
+        };
+        let mut smpl: &[u8] = &[];
+        let mut sm24: &[u8] = &[];
+        let mut hydra = Hydra::default();
+        for (id, chunk) in riff_chunks(body){
+            if &id != b"LIST" || chunk.len() < 4{
+                continue;
+            }
+            for (id, sub_chunk) in riff_chunks(&chunk[4..]){
+                match &id{
+                    b"smpl" => smpl = sub_chunk,
+                    b"sm24" => sm24 = sub_chunk,
+                    b"phdr" => hydra.phdr = sub_chunk,
+                    b"pbag" => hydra.pbag = sub_chunk,
+                    b"pgen" => hydra.pgen = sub_chunk,
+                    b"inst" => hydra.inst = sub_chunk,
+                    b"ibag" => hydra.ibag = sub_chunk,
+                    b"igen" => hydra.igen = sub_chunk,
+                    b"shdr" => hydra.shdr = sub_chunk,
+                    _ => {}
+                }
+            }
+        }
+        if hydra.phdr.is_empty() || hydra.shdr.is_empty(){
+            return Err(invalid("the SoundFont has no presets"));
+        }
+        // The sm24 chunk adds 8 more bits to each sample, but only if it matches smpl
+        let sm24 = if sm24.len() == smpl.len() / 2 {sm24} else {&[]};
+        let headers = read_sample_headers(hydra.shdr);
+        let mut samples = Vec::new();
+        let mut sample_ids = Vec::with_capacity(headers.len());
+        for header in headers.iter(){
+            sample_ids.push(decode_sample(header, smpl, sm24).map(|buffer| {
+                samples.push(Arc::new(buffer));
+                samples.len() - 1
+            }));
+        }
+        let instruments = read_instruments(&hydra);
+        let mut presets = Vec::new();
+        let records = hydra.phdr.chunks_exact(38).collect::<Vec<_>>();
+        // The last record only marks where the previous preset's zones end
+        for pair in records.windows(2){
+            let (record, next) = (pair[0], pair[1]);
+            let zones = read_zones(hydra.pbag, hydra.pgen, u16_at(record, 24), u16_at(next, 24));
+            let mut regions = Vec::new();
+            for (preset_zone, instrument_id) in with_target(&zones, INSTRUMENT){
+                for (instrument_zone, sample_id) in instruments.get(instrument_id).map_or(&[][..], |zones| zones){
+                    let sample = match (headers.get(*sample_id), sample_ids.get(*sample_id)){
+                        (Some(header), Some(Some(sample))) => (header, *sample),
+                        _ => continue,
+                    };
+                    if let Some(region) = zone_region(instrument_zone, &preset_zone, sample.0, sample.1, &samples){
+                        regions.push(region);
+                    }
+                }
+            }
+            presets.push(Sf2Preset{
+                name: name_at(record),
+                program: u16_at(record, 20),
+                bank: u16_at(record, 22),
+                instrument: Arc::new(SfzInstrument::from_regions(regions, samples.clone())),
+            });
+        }
+        presets.sort_by_key(|preset| (preset.bank, preset.program));
+        Ok(Sf2Bank{presets, samples})
+    }
+    /// Returns the presets, sorted by bank and program number
+    pub fn presets(&self)->&[Sf2Preset]{
+        &self.presets
+    }
+    /// Returns the preset at an index of `presets`
+    pub fn preset(&self, index: usize)->Option<&Sf2Preset>{
+        self.presets.get(index)
+    }
+    /// Converts every sample to `sample_rate`. Each sample is converted once and shared
+    /// by the presets again afterwards
+    pub fn resample(&mut self, sample_rate: f32, quality: ResampleQuality){
+        let samples = self.samples.iter().map(|sample| resample_shared(sample, sample_rate, quality)).collect::<Vec<_>>();
+        for preset in self.presets.iter_mut(){
+            // Only a bank that hasn't been handed to the engine yet can be changed
+            if let Some(instrument) = Arc::get_mut(&mut preset.instrument){
+                instrument.replace_samples(samples.clone());
+            }
+        }
+        self.samples = samples;
+    }
+    /// Returns whether nothing but the bank holds on to its presets and samples
+    pub fn is_unused(&self)->bool{
+        // Every preset's instrument keeps a reference to each sample
+        let references = 1 + self.presets.len();
+        self.presets.iter().all(|preset| Arc::strong_count(&preset.instrument) == 1)
+            && self.samples.iter().all(|sample| Arc::strong_count(sample) == references)
+    }
+}
+
+/// Works out the sfz region of an instrument zone played through a preset zone.
+///
+/// Preset generators are added to the instrument's, and their key and velocity ranges
+/// are intersected. Returns None if the ranges don't overlap
+fn zone_region(
+    instrument: &Generators,
+    preset: &Generators,
+    header: &SampleHeader,
+    sample: usize,
+    samples: &[Arc<SampleBuffer>],
+)->Option<SfzRegion>{
+    let value = |generator: u16, default: i32| instrument.signed(generator, default) + preset.signed(generator, 0);
+    let (lokey, hikey) = intersect(instrument.range(KEY_RANGE), preset.range(KEY_RANGE))?;
+    let (lovel, hivel) = intersect(instrument.range(VEL_RANGE), preset.range(VEL_RANGE))?;
+    let length = samples[sample].capacity() as i32;
+    let start = value(START_OFFSET, 0) + value(START_COARSE_OFFSET, 0) * 32768;
+    let end = value(END_OFFSET, 0) + value(END_COARSE_OFFSET, 0) * 32768;
+    let loop_start = header.loop_start as i32 - header.start as i32
+        + value(LOOP_START_OFFSET, 0) + value(LOOP_START_COARSE_OFFSET, 0) * 32768;
+    // The sample header's loop end is the first frame after the loop
+    let loop_end = header.loop_end as i32 - header.start as i32 - 1
+        + value(LOOP_END_OFFSET, 0) + value(LOOP_END_COARSE_OFFSET, 0) * 32768;
+    let loop_mode = match instrument.signed(SAMPLE_MODES, 0) & 3{
+        1 => SfzLoopMode::LoopContinuous,
+        3 => SfzLoopMode::LoopSustain,
+        _ => SfzLoopMode::NoLoop,
+    };
+    let root_key = match instrument.signed(OVERRIDING_ROOT_KEY, -1){
+        key @ 0..=127 => key as u8,
+        _ if header.original_pitch <= 127 => header.original_pitch,
+        _ => 60,
+    };
+    // Unset envelope times default to -12000 timecents, about a millisecond, as the spec says
+    let envelope_time = |generator: u16| Some(timecents_to_seconds(value(generator, -12000)));
+    let cutoff = value(FILTER_CUTOFF, FILTER_OFF_CENTS);
+    let exclusive_class = instrument.signed(EXCLUSIVE_CLASS, 0);
+    let mut region = SfzRegion{
+        lokey,
+        hikey,
+        lovel,
+        hivel,
+        pitch_keycenter: root_key,
+        sample,
+        offset: start.clamp(0, length) as usize,
+        end: (end != 0).then(|| (length - 1 + end).max(0) as usize),
+        loop_mode: Some(loop_mode),
+        loop_start: Some(loop_start.max(0) as usize),
+        loop_end: Some(loop_end.max(0) as usize),
+        volume: -value(INITIAL_ATTENUATION, 0).max(0) as f32 / 10.0,
+        pan: value(PAN, 0).clamp(-500, 500) as f32 / 5.0,
+        transpose: value(COARSE_TUNE, 0) as f32,
+        tune: (value(FINE_TUNE, 0) + header.pitch_correction as i32) as f32,
+        pitch_keytrack: value(SCALE_TUNING, 100) as f32,
+        ampeg_delay: envelope_time(DELAY_VOL_ENV),
+        ampeg_attack: envelope_time(ATTACK_VOL_ENV),
+        ampeg_hold: envelope_time(HOLD_VOL_ENV),
+        ampeg_decay: envelope_time(DECAY_VOL_ENV),
+        ampeg_release: envelope_time(RELEASE_VOL_ENV),
+        // Sustain is an attenuation in centibels, full level when unset
+        ampeg_sustain: Some(100.0 * 10.0_f32.powf(-value(SUSTAIN_VOL_ENV, 0).clamp(0, 1440) as f32 / 200.0)),
+        cutoff: (cutoff < FILTER_OFF_CENTS).then(|| 8.176 * 2.0_f32.powf(cutoff as f32 / 1200.0)),
+        resonance: value(FILTER_Q, 0).max(0) as f32 / 10.0,
+        ..SfzRegion::default()
+    };
+    if exclusive_class != 0{
+        region.group = exclusive_class;
+        region.off_by = Some(exclusive_class);
+        region.off_mode = SfzOffMode::Fast;
+    }
+    Some(region)
+}
+
+/// Reads the zones of every instrument, leaving out each instrument's global zone
+/// after folding it into the other zones. Each zone comes with its sample number
+fn read_instruments(hydra: &Hydra)->Vec<Vec<(Generators, usize)>>{
+    let records = hydra.inst.chunks_exact(22).collect::<Vec<_>>();
+    records.windows(2)
+        .map(|pair| {
+            let zones = read_zones(hydra.ibag, hydra.igen, u16_at(pair[0], 20), u16_at(pair[1], 20));
+            with_target(&zones, SAMPLE_ID)
+        })
+        .collect()
+}
+
+/// Splits a preset's or instrument's zones into the ones that point at an instrument or
+/// sample (the `target` generator), with the global zone's generators applied to them
+fn with_target(zones: &[Generators], target: u16)->Vec<(Generators, usize)>{
+    let mut global = Generators::new();
+    let mut targeted = Vec::new();
+    for (i, zone) in zones.iter().enumerate(){
+        match zone.get(target){
+            Some(id) => targeted.push((global.overlay(zone), id as usize)),
+            // Only the first zone can be a global zone, later ones without a target are ignored
+            None if i == 0 => global = *zone,
+            None => {}
+        }
+    }
+    targeted
+}
+
+/// Reads the generators of the zones (bags) from `first_bag` up to `end_bag`
+fn read_zones(bags: &[u8], generators: &[u8], first_bag: u16, end_bag: u16)->Vec<Generators>{
+    let generator_index = |bag: u16| bags.get(bag as usize * 4..bag as usize * 4 + 4).map(|record| u16_at(record, 0));
+    let mut zones = Vec::new();
+    for bag in first_bag..end_bag{
+        let (Some(first), Some(end)) = (generator_index(bag), generator_index(bag + 1)) else{
+            break;
+        };
+        let mut zone = Generators::new();
+        for index in first..end{
+            if let Some(record) = generators.get(index as usize * 4..index as usize * 4 + 4){
+                let generator = u16_at(record, 0) as usize;
+                if generator < NUM_GENERATORS{
+                    zone.0[generator] = Some(u16_at(record, 2));
+                }
+            }
+        }
+        zones.push(zone);
+    }
+    zones
+}
+
+/// Reads the sample headers, leaving out the terminal record
+fn read_sample_headers(shdr: &[u8])->Vec<SampleHeader>{
+    let records = shdr.chunks_exact(46).collect::<Vec<_>>();
+    records[..records.len().saturating_sub(1)]
+        .iter()
+        .map(|record| SampleHeader{
+            start: u32_at(record, 20),
+            end: u32_at(record, 24),
+            loop_start: u32_at(record, 28),
+            loop_end: u32_at(record, 32),
+            sample_rate: u32_at(record, 36),
+            original_pitch: record[40],
+            pitch_correction: record[41] as i8,
+            sample_type: u16_at(record, 44),
+        })
+        .collect()
+}
+
+/// Converts a sample's frames from the smpl (and sm24) chunk. Samples stored in
+/// ROM or pointing outside the chunk are skipped
+fn decode_sample(header: &SampleHeader, smpl: &[u8], sm24: &[u8])->Option<SampleBuffer>{
+    const ROM_SAMPLE: u16 = 0x8000;
+    let (start, end) = (header.start as usize, header.end as usize);
+    if header.sample_type & ROM_SAMPLE != 0 || end <= start || end * 2 > smpl.len(){
+        return None;
+    }
+    let frames = (start..end)
+        .map(|frame| {
+            let high = i16::from_le_bytes([smpl[frame * 2], smpl[frame * 2 + 1]]) as i32;
+            match sm24.get(frame){
+                Some(low) => ((high << 8) | *low as i32) as f32 / 8388608.0,
+                None => high as f32 / 32768.0,
+            }
+        })
+        .collect::<Vec<_>>();
+    Some(SampleBuffer::from_interleaved(&frames, 1, header.sample_rate.max(1) as f32))
+}
+
+/// Splits RIFF data into its (id, contents) chunks
+fn riff_chunks(mut data: &[u8])->Vec<([u8; 4], &[u8])>{
+    let mut chunks = Vec::new();
+    while data.len() >= 8{
+        let id = [data[0], data[1], data[2], data[3]];
+        let size = u32_at(data, 4) as usize;
+        let contents = &data[8..(8 + size).min(data.len())];
+        chunks.push((id, contents));
+        // Chunks are padded to an even length
+        data = data.get(8 + size + (size & 1)..).unwrap_or(&[]);
+    }
+    chunks
+}
+
+/// Returns the overlap of two (low, high) ranges
+fn intersect(a: (u8, u8), b: (u8, u8))->Option<(u8, u8)>{
+    let range = (a.0.max(b.0), a.1.min(b.1));
+    (range.0 <= range.1).then_some(range)
+}
+
+fn timecents_to_seconds(timecents: i32)->f32{
+    2.0_f32.powf(timecents as f32 / 1200.0)
+}
+
+fn u16_at(data: &[u8], offset: usize)->u16{
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize)->u32{
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+/// Reads the 20 byte, zero padded name at the start of a record
+fn name_at(record: &[u8])->String{
+    let name = &record[..20];
+    let end = name.iter().position(|byte| *byte == 0).unwrap_or(20);
+    String::from_utf8_lossy(&name[..end]).trim().to_string()
+}
+
+fn invalid(message: &str)->io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    fn chunk(id: &[u8], contents: &[u8])->Vec<u8>{
+        let mut data = id.to_vec();
+        data.extend((contents.len() as u32).to_le_bytes());
+        data.extend(contents);
+        if contents.len() % 2 == 1{
+            data.push(0);
+        }
+        data
+    }
+    fn record(name: &str, size: usize, fields: &[(usize, &[u8])])->Vec<u8>{
+        let mut record = vec![0; size];
+        record[..name.len()].copy_from_slice(name.as_bytes());
+        for (offset, bytes) in fields{
+            record[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+        }
+        record
+    }
+    fn generator(id: u16, amount: u16)->Vec<u8>{
+        [id.to_le_bytes(), amount.to_le_bytes()].concat()
+    }
+    /// Builds a SoundFont with one preset playing one looped sample over keys 40-80
+    fn test_file()->Vec<u8>{
+        let smpl = (0..100_i16).flat_map(|i| (i * 100).to_le_bytes()).collect::<Vec<_>>();
+        let sample = |name: &str, start: u32, end: u32| record(name, 46, &[
+            (20, &start.to_le_bytes()), (24, &end.to_le_bytes()),
+            (28, &10_u32.to_le_bytes()), (32, &90_u32.to_le_bytes()),
+            (36, &22050_u32.to_le_bytes()), (40, &[62, 255]),
+        ]);
+        let pdta = [
+            chunk(b"phdr", &[
+                record("Piano", 38, &[(20, &3_u16.to_le_bytes())]),
+                record("EOP", 38, &[(24, &2_u16.to_le_bytes())]),
+            ].concat()),
+            chunk(b"pbag", &[[0, 0, 0, 0], [1, 0, 0, 0], [3, 0, 0, 0]].concat()),
+            chunk(b"pmod", &[0; 10]),
+            chunk(b"pgen", &[
+                generator(INITIAL_ATTENUATION, 60),
+                generator(KEY_RANGE, 40 | (80 << 8)),
+                generator(INSTRUMENT, 0),
+                generator(0, 0),
+            ].concat()),
+            chunk(b"inst", &[record("Piano", 22, &[]), record("EOI", 22, &[(20, &2_u16.to_le_bytes())])].concat()),
+            chunk(b"ibag", &[[0, 0, 0, 0], [2, 0, 0, 0], [5, 0, 0, 0]].concat()),
+            chunk(b"imod", &[0; 10]),
+            chunk(b"igen", &[
+                generator(SAMPLE_MODES, 1),
+                generator(PAN, (-250_i16) as u16),
+                generator(VEL_RANGE, 100 << 8),
+                generator(ATTACK_VOL_ENV, 0),
+                generator(SAMPLE_ID, 0),
+                generator(0, 0),
+            ].concat()),
+            chunk(b"shdr", &[sample("Sample", 0, 100), sample("EOS", 0, 0)].concat()),
+        ].concat();
+        let body = [
+            b"sfbk".to_vec(),
+            chunk(b"LIST", &[b"INFO".to_vec(), chunk(b"ifil", &[2, 0, 1, 0])].concat()),
+            chunk(b"LIST", &[b"sdta".to_vec(), chunk(b"smpl", &smpl)].concat()),
+            chunk(b"LIST", &[b"pdta".to_vec(), pdta].concat()),
+        ].concat();
+        chunk(b"RIFF", &body)
+    }
+    #[test]
+    fn test_read_preset(){
+        let bank = Sf2Bank::from_bytes(&test_file()).unwrap();
+        assert_eq!(bank.presets().len(), 1);
+        let preset = bank.preset(0).unwrap();
+        assert_eq!((preset.name.as_str(), preset.program, preset.bank), ("Piano", 3, 0));
+        let regions = preset.instrument.regions_for(60, 64, 0.0).collect::<Vec<_>>();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(preset.instrument.regions_for(30, 64, 0.0).count(), 0);
+        assert_eq!(preset.instrument.regions_for(60, 110, 0.0).count(), 0);
+        let region = regions[0].1;
+        assert_eq!(region.pitch_keycenter, 62);
+        assert_eq!(region.tune, -1.0);
+        assert_eq!(region.volume, -6.0);
+        assert_eq!(region.pan, -50.0);
+        assert_eq!(region.loop_mode(), SfzLoopMode::LoopContinuous);
+        assert_eq!(region.playback_range(100), (0, 100, 10, 90));
+        assert_eq!(region.ampeg_attack, Some(1.0));
+        // Unset generators use the spec's defaults rather than the gui envelope
+        assert_eq!(region.ampeg_release, Some(timecents_to_seconds(-12000)));
+        assert_eq!(region.ampeg_sustain, Some(100.0));
+        let sample = preset.instrument.sample(region);
+        assert_eq!((sample.capacity(), sample.sample_rate()), (100, 22050.0));
+        assert!((sample.get_frac(0, 3.0) - 300.0 / 32768.0).abs() < 1e-6);
+        assert!(Sf2Bank::from_bytes(b"RIFF\0\0\0\0WAVE").is_err());
+    }
+}
@@ -0,0 +1,1078 @@
+use crate::{sample_buffer,sampler_engine,sfz_parser,sfz_preprocessor,resampler};
+use sample_buffer::SampleBuffer;
+use sampler_engine::create_buffer;
+use sfz_parser::{HeaderKind, SfzHeader, parse_note};
+use resampler::{ResampleQuality, resample_shared, scale_frame};
+use std::{collections::HashMap, io, path::{Path, PathBuf}, sync::{Arc, atomic::{AtomicU32, Ordering}}};
+
+/// A playable region of an sfz instrument
+#[derive(Clone, Debug)]
+pub struct SfzRegion{
+    pub lokey: u8,
+    pub hikey: u8,
+    pub lovel: u8,
+    pub hivel: u8,
+    pub pitch_keycenter: u8,
+    /// Index of the region's audio in the instrument's samples
+    pub sample: usize,
+    /// First frame to play
+    pub offset: usize,
+    /// Last frame to play, the end of the sample if None
+    pub end: Option<usize>,
+    pub loop_mode: Option<SfzLoopMode>,
+    /// First frame of the loop
+    pub loop_start: Option<usize>,
+    /// Last frame of the loop
+    pub loop_end: Option<usize>,
+    /// Gain in dB
+    pub volume: f32,
+    /// -100 (left) to 100 (right)
+    pub pan: f32,
+    /// Gain in percent
+    pub amplitude: f32,
+    /// How much velocity affects the gain in percent (-100 to 100)
+    pub amp_veltrack: f32,
+    /// Points of a custom velocity curve as (velocity, gain 0-1), sorted by velocity
+    pub amp_velcurve: Vec<(u8, f32)>,
+    /// Envelope times in seconds and sustain in percent. The plugin's
+    /// envelope parameters are used for the stages that aren't set
+    pub ampeg_delay: Option<f32>,
+    pub ampeg_attack: Option<f32>,
+    pub ampeg_hold: Option<f32>,
+    pub ampeg_decay: Option<f32>,
+    pub ampeg_sustain: Option<f32>,
+    pub ampeg_release: Option<f32>,
+    /// Fine tuning in cents
+    pub tune: f32,
+    /// Transposition in semitones
+    pub transpose: f32,
+    /// Cents per key away from pitch_keycenter, 0 for unpitched sounds like drums
+    pub pitch_keytrack: f32,
+    /// Cents the pitch is raised by at full velocity
+    pub pitch_veltrack: f32,
+    /// Index of the `<group>` the region was written in, used for round robins
+    pub seq_group: usize,
+    /// Number of notes in the group's round robin cycle
+    pub seq_length: u32,
+    /// Which note of the cycle the region plays on, starting from 1
+    pub seq_position: u32,
+    /// The region plays when a random number from 0 to 1 falls between lorand and hirand
+    pub lorand: f32,
+    pub hirand: f32,
+    /// Which note events start the region
+    pub trigger: SfzTrigger,
+    /// dB a release region is turned down by for each second the key was held
+    pub rt_decay: f32,
+    /// Range of keys that switch articulations instead of playing
+    pub sw_lokey: Option<u8>,
+    pub sw_hikey: Option<u8>,
+    /// The region only plays if this was the last key switch pressed
+    pub sw_last: Option<u8>,
+    /// The region only plays while this key is held
+    pub sw_down: Option<u8>,
+    /// The region only plays while this key isn't held
+    pub sw_up: Option<u8>,
+    /// The key switch that is active before any has been pressed
+    pub sw_default: Option<u8>,
+    /// Name of the articulation chosen by sw_last
+    pub sw_label: Option<String>,
+    /// Exclusive group number, used by off_by and polyphony
+    pub group: i32,
+    /// Starting a region of this group stops the region
+    pub off_by: Option<i32>,
+    /// How the region stops when it's turned off by another group
+    pub off_mode: SfzOffMode,
+    /// Most voices the region's group can play at once
+    pub polyphony: Option<u32>,
+    /// The region only plays while each listed CC is within its range, as (cc, low, high)
+    pub cc_ranges: Vec<(u8, u8, u8)>,
+    /// CC modulation as (cc, amount at the CC's full value). Amplitude is in percent
+    /// and scales the gain, volume is in dB and the others add to the region's own value
+    pub amplitude_oncc: Vec<(u8, f32)>,
+    pub volume_oncc: Vec<(u8, f32)>,
+    pub pan_oncc: Vec<(u8, f32)>,
+    /// In cents
+    pub pitch_oncc: Vec<(u8, f32)>,
+    /// In cents
+    pub cutoff_oncc: Vec<(u8, f32)>,
+    /// Lowpass cutoff in Hz, no filter if None
+    pub cutoff: Option<f32>,
+    /// Lowpass resonance in dB
+    pub resonance: f32,
+    /// Velocity and key ranges over which the region fades in and fades out, as (low, high)
+    pub xfin_vel: (u8, u8),
+    pub xfout_vel: (u8, u8),
+    pub xfin_key: (u8, u8),
+    pub xfout_key: (u8, u8),
+    pub xf_velcurve: SfzCrossfadeCurve,
+    pub xf_keycurve: SfzCrossfadeCurve,
+}
+
+/// How a region plays through its sample
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SfzLoopMode{
+    NoLoop, // Plays until the end of the sample or the end of the release
+    OneShot, // Plays the whole sample, ignoring note offs
+    LoopContinuous, // Loops until the end of the release
+    LoopSustain, // Loops while the key is held, then plays on to the end of the sample
+}
+
+/// When a region starts playing
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SfzTrigger{
+    Attack, // On every note on
+    Release, // On note off, with the velocity of the note on
+    First, // On note on when no other keys are held
+    Legato, // On note on while other keys are held
+}
+
+impl SfzTrigger{
+    pub fn from_name(name: &str)->Option<Self>{
+        match name{
+            "attack" => Some(SfzTrigger::Attack),
+            // Without a sustain pedal release_key behaves like release
+            "release" | "release_key" => Some(SfzTrigger::Release),
+            "first" => Some(SfzTrigger::First),
+            "legato" => Some(SfzTrigger::Legato),
+            _ => None,
+        }
+    }
+    /// Returns whether a region with this trigger starts on a note on (or a note off
+    /// if `release` is true), given whether any other keys are held
+    pub fn plays_on(&self, release: bool, other_keys_held: bool)->bool{
+        match self{
+            SfzTrigger::Attack => !release,
+            SfzTrigger::Release => release,
+            SfzTrigger::First => !release && !other_keys_held,
+            SfzTrigger::Legato => !release && other_keys_held,
+        }
+    }
+}
+
+/// The shape of a crossfade between regions
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SfzCrossfadeCurve{
+    Power, // Keeps the loudness steady across the fade
+    Gain, // Fades the gain in a straight line
+}
+
+/// How a region stops when a region of its off_by group starts
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SfzOffMode{
+    Fast, // Fades out in a few milliseconds
+    Normal, // Goes into its release stage
+}
+
+impl SfzCrossfadeCurve{
+    fn from_name(name: &str)->Option<Self>{
+        match name{
+            "power" => Some(SfzCrossfadeCurve::Power),
+            "gain" => Some(SfzCrossfadeCurve::Gain),
+            _ => None,
+        }
+    }
+    /// Returns the gain at a position from 0 (silent) to 1 (full level) through a fade
+    fn gain(&self, position: f32)->f32{
+        match self{
+            SfzCrossfadeCurve::Power => position.sqrt(),
+            SfzCrossfadeCurve::Gain => position,
+        }
+    }
+}
+
+impl SfzLoopMode{
+    fn from_name(name: &str)->Option<Self>{
+        match name{
+            "no_loop" => Some(SfzLoopMode::NoLoop),
+            "one_shot" => Some(SfzLoopMode::OneShot),
+            "loop_continuous" => Some(SfzLoopMode::LoopContinuous),
+            "loop_sustain" => Some(SfzLoopMode::LoopSustain),
+            _ => None,
+        }
+    }
+}
+
+impl Default for SfzRegion{
+    fn default()->Self{
+        SfzRegion{
+            lokey: 0,
+            hikey: 127,
+            lovel: 0,
+            hivel: 127,
+            pitch_keycenter: 60,
+            sample: 0,
+            offset: 0,
+            end: None,
+            loop_mode: None,
+            loop_start: None,
+            loop_end: None,
+            volume: 0.0,
+            pan: 0.0,
+            amplitude: 100.0,
+            amp_veltrack: 100.0,
+            amp_velcurve: Vec::new(),
+            ampeg_delay: None,
+            ampeg_attack: None,
+            ampeg_hold: None,
+            ampeg_decay: None,
+            ampeg_sustain: None,
+            ampeg_release: None,
+            tune: 0.0,
+            transpose: 0.0,
+            pitch_keytrack: 100.0,
+            pitch_veltrack: 0.0,
+            seq_group: 0,
+            seq_length: 1,
+            seq_position: 1,
+            lorand: 0.0,
+            hirand: 1.0,
+            trigger: SfzTrigger::Attack,
+            rt_decay: 0.0,
+            sw_lokey: None,
+            sw_hikey: None,
+            sw_last: None,
+            sw_down: None,
+            sw_up: None,
+            sw_default: None,
+            sw_label: None,
+            group: 0,
+            off_by: None,
+            off_mode: SfzOffMode::Fast,
+            polyphony: None,
+            cc_ranges: Vec::new(),
+            amplitude_oncc: Vec::new(),
+            volume_oncc: Vec::new(),
+            pan_oncc: Vec::new(),
+            pitch_oncc: Vec::new(),
+            cutoff_oncc: Vec::new(),
+            cutoff: None,
+            resonance: 0.0,
+            xfin_vel: (0, 0),
+            xfout_vel: (127, 127),
+            xfin_key: (0, 0),
+            xfout_key: (127, 127),
+            xf_velcurve: SfzCrossfadeCurve::Power,
+            xf_keycurve: SfzCrossfadeCurve::Power,
+        }
+    }
+}
+
+/// The regions of an sfz file with every sample decoded up front.
+///
+/// Regions are looked up through a table indexed by midi note, so finding the
+/// regions for a note on doesn't walk the whole instrument or allocate
+pub struct SfzInstrument{
+    regions: Vec<SfzRegion>,
+    samples: Vec<Arc<SampleBuffer>>,
+    key_map: Vec<Vec<usize>>,
+    missing_samples: Vec<String>,
+    /// Round robin counter of each group. Atomic so the audio thread can
+    /// advance them while the instrument is shared
+    sequences: Vec<AtomicU32>,
+    /// Keys that switch articulations and don't make a sound
+    keyswitches: [bool; 128],
+    default_keyswitch: Option<u8>,
+    keyswitch_labels: Vec<(u8, String)>,
+    /// Problems found while reading the file, such as missing includes or unknown headers
+    errors: Vec<String>,
+    /// CC values set by `set_ccN` in the `<control>` header, from 0 to 1
+    cc_defaults: Vec<(u8, f32)>,
+}
+
+impl SfzRegion{
+    /// Applies an opcode to the region. Unknown opcodes and bad values are ignored
+    fn apply_opcode(&mut self, name: &str, value: &str){
+        match name{
+            "lokey" => if let Some(note) = parse_note(value) {self.lokey = note},
+            "hikey" => if let Some(note) = parse_note(value) {self.hikey = note},
+            "key" => if let Some(note) = parse_note(value){
+                self.lokey = note;
+                self.hikey = note;
+                self.pitch_keycenter = note;
+            },
+            "pitch_keycenter" => if let Some(note) = parse_note(value) {self.pitch_keycenter = note},
+            "lovel" => if let Ok(velocity) = value.parse() {self.lovel = velocity},
+            "hivel" => if let Ok(velocity) = value.parse() {self.hivel = velocity},
+            "offset" => if let Ok(frame) = value.parse() {self.offset = frame},
+            "end" => if let Ok(frame) = value.parse() {self.end = Some(frame)},
+            "loop_mode" | "loopmode" => self.loop_mode = SfzLoopMode::from_name(value),
+            "loop_start" | "loopstart" => if let Ok(frame) = value.parse() {self.loop_start = Some(frame)},
+            "loop_end" | "loopend" => if let Ok(frame) = value.parse() {self.loop_end = Some(frame)},
+            "volume" => if let Ok(volume) = value.parse() {self.volume = volume},
+            "pan" => if let Ok(pan) = value.parse() {self.pan = pan},
+            "amplitude" => if let Ok(amplitude) = value.parse() {self.amplitude = amplitude},
+            "amp_veltrack" => if let Ok(veltrack) = value.parse() {self.amp_veltrack = veltrack},
+            "ampeg_delay" => self.ampeg_delay = value.parse().ok(),
+            "ampeg_attack" => self.ampeg_attack = value.parse().ok(),
+            "ampeg_hold" => self.ampeg_hold = value.parse().ok(),
+            "ampeg_decay" => self.ampeg_decay = value.parse().ok(),
+            "ampeg_sustain" => self.ampeg_sustain = value.parse().ok(),
+            "ampeg_release" => self.ampeg_release = value.parse().ok(),
+            "tune" | "pitch" => if let Ok(tune) = value.parse() {self.tune = tune},
+            "transpose" => if let Ok(transpose) = value.parse() {self.transpose = transpose},
+            "pitch_keytrack" => if let Ok(keytrack) = value.parse() {self.pitch_keytrack = keytrack},
+            "pitch_veltrack" => if let Ok(veltrack) = value.parse() {self.pitch_veltrack = veltrack},
+            "seq_length" => if let Ok(length) = value.parse::<u32>() {self.seq_length = length.max(1)},
+            "seq_position" => if let Ok(position) = value.parse::<u32>() {self.seq_position = position.max(1)},
+            "lorand" => if let Ok(random) = value.parse() {self.lorand = random},
+            "hirand" => if let Ok(random) = value.parse() {self.hirand = random},
+            "trigger" => if let Some(trigger) = SfzTrigger::from_name(value) {self.trigger = trigger},
+            "rt_decay" => if let Ok(decay) = value.parse() {self.rt_decay = decay},
+            "sw_lokey" => self.sw_lokey = parse_note(value),
+            "sw_hikey" => self.sw_hikey = parse_note(value),
+            "sw_last" => self.sw_last = parse_note(value),
+            "sw_down" => self.sw_down = parse_note(value),
+            "sw_up" => self.sw_up = parse_note(value),
+            "sw_default" => self.sw_default = parse_note(value),
+            "sw_label" => self.sw_label = Some(value.to_string()),
+            "group" => if let Ok(group) = value.parse() {self.group = group},
+            "off_by" => self.off_by = value.parse().ok(),
+            "off_mode" => match value{
+                "fast" => self.off_mode = SfzOffMode::Fast,
+                "normal" => self.off_mode = SfzOffMode::Normal,
+                _ => {}
+            },
+            "polyphony" => self.polyphony = value.parse().ok(),
+            "cutoff" => self.cutoff = value.parse().ok(),
+            "resonance" => if let Ok(resonance) = value.parse() {self.resonance = resonance},
+            "xfin_lovel" => if let Ok(velocity) = value.parse() {self.xfin_vel.0 = velocity},
+            "xfin_hivel" => if let Ok(velocity) = value.parse() {self.xfin_vel.1 = velocity},
+            "xfout_lovel" => if let Ok(velocity) = value.parse() {self.xfout_vel.0 = velocity},
+            "xfout_hivel" => if let Ok(velocity) = value.parse() {self.xfout_vel.1 = velocity},
+            "xfin_lokey" => if let Some(note) = parse_note(value) {self.xfin_key.0 = note},
+            "xfin_hikey" => if let Some(note) = parse_note(value) {self.xfin_key.1 = note},
+            "xfout_lokey" => if let Some(note) = parse_note(value) {self.xfout_key.0 = note},
+            "xfout_hikey" => if let Some(note) = parse_note(value) {self.xfout_key.1 = note},
+            "xf_velcurve" => if let Some(curve) = SfzCrossfadeCurve::from_name(value) {self.xf_velcurve = curve},
+            "xf_keycurve" => if let Some(curve) = SfzCrossfadeCurve::from_name(value) {self.xf_keycurve = curve},
+            _ =>{
+                if let Some(velocity) = name.strip_prefix("amp_velcurve_"){
+                    if let (Ok(velocity), Ok(gain)) = (velocity.parse::<u8>(), value.parse::<f32>()){
+                        self.amp_velcurve.retain(|(point, _)| *point != velocity);
+                        self.amp_velcurve.push((velocity.min(127), gain));
+                        self.amp_velcurve.sort_by_key(|(point, _)| *point);
+                    }
+                }else if let Some(cc) = cc_number(name, "locc"){
+                    if let Ok(low) = value.parse::<u8>(){
+                        self.cc_range(cc).1 = low;
+                    }
+                }else if let Some(cc) = cc_number(name, "hicc"){
+                    if let Ok(high) = value.parse::<u8>(){
+                        self.cc_range(cc).2 = high;
+                    }
+                }else if let Ok(amount) = value.parse::<f32>(){
+                    let modulation = if let Some(cc) = cc_number(name, "amplitude_oncc"){
+                        Some((&mut self.amplitude_oncc, cc))
+                    }else if let Some(cc) = cc_number(name, "volume_oncc"){
+                        Some((&mut self.volume_oncc, cc))
+                    }else if let Some(cc) = cc_number(name, "pan_oncc"){
+                        Some((&mut self.pan_oncc, cc))
+                    }else if let Some(cc) = cc_number(name, "pitch_oncc").or_else(|| cc_number(name, "tune_oncc")){
+                        Some((&mut self.pitch_oncc, cc))
+                    }else if let Some(cc) = cc_number(name, "cutoff_oncc"){
+                        Some((&mut self.cutoff_oncc, cc))
+                    }else{
+                        None
+                    };
+                    if let Some((modulation, cc)) = modulation{
+                        modulation.retain(|(modulated, _)| *modulated != cc);
+                        modulation.push((cc, amount));
+                    }
+                }
+            }
+        }
+    }
+    /// Returns the range of a CC the region plays in, adding a full range if there isn't one
+    fn cc_range(&mut self, cc: u8)->&mut (u8, u8, u8){
+        let index = match self.cc_ranges.iter().position(|(ranged, _, _)| *ranged == cc){
+            Some(index) => index,
+            None =>{
+                self.cc_ranges.push((cc, 0, 127));
+                self.cc_ranges.len() - 1
+            }
+        };
+        &mut self.cc_ranges[index]
+    }
+    /// Returns whether every CC the region has a range for is inside it. CC values are 0-1
+    pub fn is_in_cc_range(&self, cc_values: &[f32; 128])->bool{
+        self.cc_ranges.iter().all(|(cc, low, high)| {
+            let value = (cc_values[*cc as usize] * 127.0).round() as u8;
+            value >= *low && value <= *high
+        })
+    }
+    /// Returns the linear gain the CCs give the region through amplitude_onccN and volume_onccN
+    pub fn cc_gain(&self, cc_values: &[f32; 128])->f32{
+        let amplitude = self.amplitude_oncc.iter()
+            .map(|(cc, amount)| cc_values[*cc as usize] * amount * 0.01)
+            .product::<f32>();
+        amplitude * 10.0_f32.powf(cc_modulation(&self.volume_oncc, cc_values) / 20.0)
+    }
+    /// Returns the pan (-100 to 100) after CC modulation
+    pub fn cc_pan(&self, cc_values: &[f32; 128])->f32{
+        self.pan + cc_modulation(&self.pan_oncc, cc_values)
+    }
+    /// Returns the cents the CCs move the pitch by
+    pub fn cc_pitch(&self, cc_values: &[f32; 128])->f32{
+        cc_modulation(&self.pitch_oncc, cc_values)
+    }
+    /// Returns the lowpass cutoff in Hz after CC modulation, or None if the region has no filter
+    pub fn cc_cutoff(&self, cc_values: &[f32; 128])->Option<f32>{
+        let cents = cc_modulation(&self.cutoff_oncc, cc_values);
+        self.cutoff.map(|cutoff| cutoff * 2.0_f32.powf(cents / 1200.0))
+    }
+    /// Returns the linear gain of the region for a note of the given velocity (0-127),
+    /// combining volume, amplitude and velocity tracking
+    pub fn gain(&self, velocity: u8)->f32{
+        let track = self.amp_veltrack.clamp(-100.0, 100.0) * 0.01;
+        // Negative tracking makes soft notes louder
+        let velocity = if track < 0.0 {127 - velocity.min(127)} else {velocity};
+        let velocity_gain = 1.0 - track.abs() + track.abs() * self.velocity_curve(velocity);
+        let volume_gain = 10.0_f32.powf(self.volume / 20.0);
+        volume_gain * self.amplitude * 0.01 * velocity_gain
+    }
+    /// Evaluates the velocity curve. Without amp_velcurve points the curve is the
+    /// standard sfz square curve, otherwise the points are joined with straight lines
+    fn velocity_curve(&self, velocity: u8)->f32{
+        if self.amp_velcurve.is_empty(){
+            let x = velocity as f32 / 127.0;
+            return x * x;
+        }
+        let mut previous = (0_u8, 0.0_f32);
+        for point in self.amp_velcurve.iter().copied().chain(std::iter::once((127, 1.0))){
+            if velocity <= point.0{
+                if point.0 == previous.0{
+                    return point.1;
+                }
+                let t = (velocity - previous.0) as f32 / (point.0 - previous.0) as f32;
+                return previous.1 + t * (point.1 - previous.1);
+            }
+            previous = point;
+        }
+        previous.1
+    }
+    /// Returns the linear gain a release region is turned down by after the key was held for `held_time` seconds
+    pub fn release_attenuation(&self, held_time: f32)->f32{
+        10.0_f32.powf(-self.rt_decay * held_time.max(0.0) / 20.0)
+    }
+    /// Returns the linear gain of the region's velocity and key crossfades for a note
+    pub fn crossfade_gain(&self, note: u8, velocity: u8)->f32{
+        let velocity_gain = self.xf_velcurve.gain(fade_in(velocity, self.xfin_vel))
+            * self.xf_velcurve.gain(1.0 - fade_out(velocity, self.xfout_vel));
+        let key_gain = self.xf_keycurve.gain(fade_in(note, self.xfin_key))
+            * self.xf_keycurve.gain(1.0 - fade_out(note, self.xfout_key));
+        velocity_gain * key_gain
+    }
+    /// Returns the pitch offset in cents for a note of the given velocity (0-127)
+    pub fn pitch_offset(&self, velocity: u8)->f32{
+        self.tune + self.transpose * 100.0 + self.pitch_veltrack * velocity.min(127) as f32 / 127.0
+    }
+    /// Returns the region's envelope, using the given values (in the order
+    /// attack, decay, sustain, release) for the stages the region doesn't set.
+    ///
+    /// Returns (delay, attack, hold, decay, sustain, release) in seconds, with sustain 0-1
+    pub fn envelope(&self, defaults: (f32, f32, f32, f32))->(f32, f32, f32, f32, f32, f32){
+        let (attack, decay, sustain, release) = defaults;
+        (
+            self.ampeg_delay.unwrap_or(0.0),
+            self.ampeg_attack.unwrap_or(attack),
+            self.ampeg_hold.unwrap_or(0.0),
+            self.ampeg_decay.unwrap_or(decay),
+            self.ampeg_sustain.map_or(sustain, |sustain| sustain * 0.01),
+            self.ampeg_release.unwrap_or(release),
+        )
+    }
+    /// Returns the region's playback range in frames, clamped to a sample of `length` frames.
+    ///
+    /// Returns (start, end, loop_start, loop_end), where the ends are exclusive
+    pub fn playback_range(&self, length: usize)->(usize, usize, usize, usize){
+        let end = self.end.map_or(length, |end| (end + 1).min(length));
+        let start = self.offset.min(end);
+        let loop_end = self.loop_end.map_or(end, |loop_end| (loop_end + 1).min(end));
+        let loop_start = self.loop_start.unwrap_or(0).min(loop_end.saturating_sub(1));
+        (start, end, loop_start, loop_end)
+    }
+    /// Returns whether the region plays for a random number (0-1) and the
+    /// count of notes its group has played
+    fn is_selected(&self, random: f32, sequence: u32)->bool{
+        let in_random_range = random >= self.lorand && (random < self.hirand || self.hirand >= 1.0);
+        in_random_range && sequence % self.seq_length + 1 == self.seq_position
+    }
+    /// Returns whether the key switches let the region play, given the last key switch
+    /// pressed and a function that tells whether a key is held
+    pub fn is_switched_on(&self, last_keyswitch: Option<u8>, is_held: impl Fn(u8)->bool)->bool{
+        self.sw_last.map_or(true, |key| last_keyswitch == Some(key))
+            && self.sw_down.map_or(true, |key| is_held(key))
+            && self.sw_up.map_or(true, |key| !is_held(key))
+    }
+    /// Returns the loop mode, which defaults to looping when loop points are given
+    pub fn loop_mode(&self)->SfzLoopMode{
+        match self.loop_mode{
+            Some(mode) => mode,
+            None if self.loop_start.is_some() || self.loop_end.is_some() => SfzLoopMode::LoopContinuous,
+            None => SfzLoopMode::NoLoop,
+        }
+    }
+}
+
+impl SfzInstrument{
+    /// Creates an instrument without any regions
+    pub fn new()->Self{
+        SfzInstrument{
+            regions: Vec::new(),
+            samples: Vec::new(),
+            key_map: vec![Vec::new(); 128],
+            missing_samples: Vec::new(),
+            sequences: Vec::new(),
+            keyswitches: [false; 128],
+            default_keyswitch: None,
+            keyswitch_labels: Vec::new(),
+            errors: Vec::new(),
+            cc_defaults: Vec::new(),
+        }
+    }
+    /// Reads an sfz file, builds its region table and decodes every sample it uses.
+    ///
+    /// Sample paths are resolved relative to the sfz file's folder and the
+    /// `default_path` of the `<control>` header. Regions whose sample can't be
+    /// found are skipped and listed in `missing_samples`
+    pub fn from_file(path: &Path)->io::Result<Self>{
+        let mut errors = Vec::new();
+        let text = sfz_preprocessor::preprocess_file(path, &mut errors)?;
+        let headers = sfz_parser::parse(&text, &mut errors);
+        let sfz_dir = path.parent().unwrap_or(Path::new(""));
+        let mut sfz = SfzInstrument::from_headers(&headers, sfz_dir);
+        sfz.cc_defaults = collect_cc_defaults(&headers);
+        errors.append(&mut sfz.errors);
+        sfz.errors = errors;
+        Ok(sfz)
+    }
+    /// Builds the region table from parsed headers. Regions that share a file share the decoded buffer.
+    /// Samples that can't be decoded are listed in `errors`
+    pub fn from_headers(headers: &[SfzHeader], sfz_dir: &Path)->Self{
+        let mut sfz = SfzInstrument::new();
+        let mut sample_ids = HashMap::<PathBuf, usize>::new();
+        let parsed_regions = collect_regions(headers);
+        let num_groups = parsed_regions.iter().map(|parsed| parsed.region.seq_group + 1).max().unwrap_or(0);
+        sfz.sequences = (0..num_groups).map(|_| AtomicU32::new(0)).collect();
+        for parsed in parsed_regions{
+            let mut region = parsed.region;
+            let file_path = resolve_sample_path(sfz_dir, &parsed.default_path, &parsed.sample);
+            region.sample = match sample_ids.get(&file_path){
+                Some(id) => *id,
+                None =>{
+                    if !file_path.is_file(){
+                        let missing = file_path.display().to_string();
+                        if !sfz.missing_samples.contains(&missing){
+                            sfz.missing_samples.push(missing);
+                        }
+                        continue;
+                    }
+                    match create_buffer(&file_path.to_string_lossy()){
+                        Ok((buffer, _sample_rate)) =>{
+                            sfz.samples.push(Arc::new(buffer));
+                            sample_ids.insert(file_path, sfz.samples.len() - 1);
+                            sfz.samples.len() - 1
+                        },
+                        Err(e) =>{
+                            sfz.errors.push(format!("Could not decode {}: {}", file_path.display(), e));
+                            // Remember the failure so the file isn't decoded again for its other regions
+                            sample_ids.insert(file_path, usize::MAX);
+                            continue;
+                        }
+                    }
+                }
+            };
+            if region.sample != usize::MAX{
+                sfz.add_region(region);
+            }
+        }
+        sfz
+    }
+    /// Builds an instrument from regions that were read from another format.
+    /// Each region's `sample` is an index into `samples`
+    pub fn from_regions(regions: Vec<SfzRegion>, samples: Vec<Arc<SampleBuffer>>)->Self{
+        let mut instrument = SfzInstrument::new();
+        let num_groups = regions.iter().map(|region| region.seq_group + 1).max().unwrap_or(0);
+        instrument.sequences = (0..num_groups).map(|_| AtomicU32::new(0)).collect();
+        instrument.samples = samples;
+        for region in regions{
+            instrument.add_region(region);
+        }
+        instrument
+    }
+    /// Adds a region and registers it in the key table
+    fn add_region(&mut self, region: SfzRegion){
+        let id = self.regions.len();
+        for note in region.lokey.min(127)..=region.hikey.min(127){
+            self.key_map[note as usize].push(id);
+        }
+        if let (Some(lokey), Some(hikey)) = (region.sw_lokey, region.sw_hikey){
+            for note in lokey.min(127)..=hikey.min(127){
+                self.keyswitches[note as usize] = true;
+            }
+        }
+        for key in [region.sw_last, region.sw_down, region.sw_up].into_iter().flatten(){
+            self.keyswitches[key.min(127) as usize] = true;
+        }
+        if self.default_keyswitch.is_none(){
+            self.default_keyswitch = region.sw_default;
+        }
+        if let (Some(key), Some(label)) = (region.sw_last, &region.sw_label){
+            if !self.keyswitch_labels.iter().any(|(labelled, _)| *labelled == key){
+                self.keyswitch_labels.push((key, label.clone()));
+            }
+        }
+        self.regions.push(region);
+    }
+    /// Returns the regions whose key and velocity (0-127) ranges contain the note, with their indices
+    fn regions_in_range(&self, note: u8, velocity: u8)->impl Iterator<Item = (usize, &SfzRegion)>{
+        self.key_map[(note as usize).min(127)]
+            .iter()
+            .map(|id| (*id, &self.regions[*id]))
+            .filter(move |(_, region)| velocity >= region.lovel && velocity <= region.hivel)
+    }
+    /// Returns the regions that should play for a note and velocity (0-127), with their indices.
+    ///
+    /// `random` is a number from 0 to 1 picked once per note on, so only one of a
+    /// set of random layers plays. Round robins are chosen from the group counters,
+    /// which should be moved on afterwards with `advance_sequences`
+    pub fn regions_for(&self, note: u8, velocity: u8, random: f32)->impl Iterator<Item = (usize, &SfzRegion)>{
+        self.regions_in_range(note, velocity)
+            .filter(move |(_, region)| region.is_selected(random, self.sequences[region.seq_group].load(Ordering::Relaxed)))
+    }
+    /// Counts a note on for every group that has a region in range of the note
+    pub fn advance_sequences(&self, note: u8, velocity: u8){
+        // Regions are stored in file order, so the regions of a group come one after another
+        let mut previous_group = None;
+        for (_, region) in self.regions_in_range(note, velocity){
+            if previous_group != Some(region.seq_group){
+                self.sequences[region.seq_group].fetch_add(1, Ordering::Relaxed);
+                previous_group = Some(region.seq_group);
+            }
+        }
+    }
+    /// Returns the problems found while reading the file
+    pub fn errors(&self)->&[String]{
+        &self.errors
+    }
+    /// Returns the CC values the instrument sets when it's loaded, as (cc, value 0-1)
+    pub fn cc_defaults(&self)->&[(u8, f32)]{
+        &self.cc_defaults
+    }
+    /// Returns a region by its index, as given by `regions_for`
+    pub fn region(&self, id: usize)->&SfzRegion{
+        &self.regions[id]
+    }
+    /// Returns whether the key switches articulations instead of playing
+    pub fn is_keyswitch(&self, note: u8)->bool{
+        self.keyswitches[(note as usize).min(127)]
+    }
+    /// Returns the key switch that is active when the instrument is loaded
+    pub fn default_keyswitch(&self)->Option<u8>{
+        self.default_keyswitch
+    }
+    /// Returns the articulation names given by `sw_label`, with the key that selects each one
+    pub fn keyswitch_labels(&self)->&[(u8, String)]{
+        &self.keyswitch_labels
+    }
+    /// Returns the decoded audio of a region
+    pub fn sample(&self, region: &SfzRegion)->&Arc<SampleBuffer>{
+        &self.samples[region.sample]
+    }
+    /// Returns the paths of the samples that couldn't be found while loading
+    pub fn missing_samples(&self)->&[String]{
+        &self.missing_samples
+    }
+    /// Records the problems found while reading an instrument from another format
+    pub fn with_problems(mut self, errors: Vec<String>, missing_samples: Vec<String>)->Self{
+        self.errors = errors;
+        self.missing_samples = missing_samples;
+        self
+    }
+    /// Returns whether nothing but this instrument holds on to its samples
+    pub fn is_unused(&self)->bool{
+        self.samples.iter().all(|sample| Arc::strong_count(sample) == 1)
+    }
+    /// Converts every sample to `sample_rate`
+    pub fn resample(&mut self, sample_rate: f32, quality: ResampleQuality){
+        let samples = self.samples.iter().map(|sample| resample_shared(sample, sample_rate, quality)).collect();
+        self.replace_samples(samples);
+    }
+    /// Swaps in converted versions of the samples. Offsets, ends and loop points of the
+    /// regions are moved by how much each sample's rate changed
+    pub fn replace_samples(&mut self, samples: Vec<Arc<SampleBuffer>>){
+        for region in self.regions.iter_mut(){
+            let (Some(old), Some(new)) = (self.samples.get(region.sample), samples.get(region.sample)) else{
+                continue;
+            };
+            if old.sample_rate() == new.sample_rate(){
+                continue;
+            }
+            let ratio = new.sample_rate() as f64 / old.sample_rate() as f64;
+            // Ends and loop ends are the last frame played, so scale the frame after them
+            let scale_last = |frame: usize| scale_frame(frame + 1, ratio).saturating_sub(1);
+            region.offset = scale_frame(region.offset, ratio);
+            region.end = region.end.map(scale_last);
+            region.loop_start = region.loop_start.map(|frame| scale_frame(frame, ratio));
+            region.loop_end = region.loop_end.map(scale_last);
+        }
+        self.samples = samples;
+    }
+}
+
+/// A region read from the headers whose sample hasn't been loaded yet
+struct ParsedRegion{
+    region: SfzRegion,
+    sample: String,
+    default_path: String,
+}
+
+/// Works out the opcodes of every region that has a sample.
+///
+/// Opcodes set on `<global>`, `<master>` and `<group>` headers apply to all the regions
+/// that follow them, unless a lower level header or the region sets them again. A new
+/// `<global>` clears the master and group opcodes, and a new `<master>` clears the group
+/// opcodes. `<control>` opcodes such as `default_path` apply until they are set again
+fn collect_regions(headers: &[SfzHeader])->Vec<ParsedRegion>{
+    let mut regions = Vec::new();
+    let mut default_path = String::new();
+    let mut global_opcodes: &[(String, String)] = &[];
+    let mut master_opcodes: &[(String, String)] = &[];
+    let mut group_opcodes: &[(String, String)] = &[];
+    let mut group = 0;
+    for header in headers.iter(){
+        match header.kind{
+            HeaderKind::Control =>{
+                for (name, value) in header.opcodes.iter(){
+                    if name == "default_path"{
+                        default_path = value.clone();
+                    }
+                }
+            },
+            HeaderKind::Global =>{
+                global_opcodes = &header.opcodes;
+                master_opcodes = &[];
+                group_opcodes = &[];
+                group += 1;
+            },
+            HeaderKind::Master =>{
+                master_opcodes = &header.opcodes;
+                group_opcodes = &[];
+                group += 1;
+            },
+            HeaderKind::Group =>{
+                group_opcodes = &header.opcodes;
+                group += 1;
+            },
+            HeaderKind::Region =>{
+                let mut region = SfzRegion{seq_group: group, ..SfzRegion::default()};
+                let mut sample = None;
+                let opcodes = global_opcodes.iter()
+                    .chain(master_opcodes)
+                    .chain(group_opcodes)
+                    .chain(&header.opcodes);
+                for (name, value) in opcodes{
+                    if name == "sample"{
+                        sample = Some(value.clone());
+                    }else{
+                        region.apply_opcode(name, value);
+                    }
+                }
+                // Regions without a sample can't make any sound
+                if let Some(sample) = sample{
+                    regions.push(ParsedRegion{region, sample, default_path: default_path.clone()});
+                }
+            },
+            HeaderKind::Other => {}
+        }
+    }
+    regions
+}
+
+/// Returns how far (0-1) a value is through a fade in over the range (low, high)
+fn fade_in(value: u8, (low, high): (u8, u8))->f32{
+    if value >= high{
+        1.0
+    }else if value <= low{
+        0.0
+    }else{
+        (value - low) as f32 / (high - low) as f32
+    }
+}
+
+/// Returns how far (0-1) a value is through a fade out over the range (low, high)
+fn fade_out(value: u8, (low, high): (u8, u8))->f32{
+    if value <= low{
+        0.0
+    }else if value >= high{
+        1.0
+    }else{
+        (value - low) as f32 / (high - low) as f32
+    }
+}
+
+/// Reads the `set_ccN` opcodes of the `<control>` headers, as (cc, value 0-1)
+fn collect_cc_defaults(headers: &[SfzHeader])->Vec<(u8, f32)>{
+    let mut defaults = Vec::<(u8, f32)>::new();
+    for header in headers.iter().filter(|header| header.kind == HeaderKind::Control){
+        for (name, value) in header.opcodes.iter(){
+            if let (Some(cc), Ok(value)) = (cc_number(name, "set_cc"), value.parse::<f32>()){
+                defaults.retain(|(set, _)| *set != cc);
+                defaults.push((cc, (value / 127.0).clamp(0.0, 1.0)));
+            }
+        }
+    }
+    defaults
+}
+
+/// Returns the CC number of an opcode such as `locc64` if its name starts with `prefix`
+fn cc_number(name: &str, prefix: &str)->Option<u8>{
+    name.strip_prefix(prefix)?.parse::<u8>().ok().filter(|cc| *cc <= 127)
+}
+
+/// Adds up CC modulation given as (cc, amount at the CC's full value)
+fn cc_modulation(modulation: &[(u8, f32)], cc_values: &[f32; 128])->f32{
+    modulation.iter().map(|(cc, amount)| cc_values[*cc as usize] * amount).sum()
+}
+
+/// Finds the file a sample opcode points to. Windows style separators are converted,
+/// and relative paths start from the sfz file's folder plus `default_path`
+fn resolve_sample_path(sfz_dir: &Path, default_path: &str, sample: &str)->PathBuf{
+    let sample = sample.replace('\\', "/");
+    let default_path = default_path.replace('\\', "/");
+    let path = Path::new(&default_path).join(&sample);
+    if path.is_absolute(){
+        path
+    }else{
+        sfz_dir.join(path)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::{interpolation::Interpolation, loader::LoadedInstrument, sampler_voice::SustainModes};
+    use sampler_engine::{EngineParams, SamplerEngine};
+    macro_rules! assert_close {
+        ($left:expr, $right:expr, $epsilon:expr) => {{
+            let (left, right, epsilon) = ($left, $right, $epsilon);
+            assert!(
+                (left - right).abs() <= epsilon,
+                "{} is not close to {} within an epsilon of {}",
+                left,
+                right,
+                epsilon
+            );
+        }};
+    }
+    #[test]
+    fn test_gain(){
+        let mut region = SfzRegion::default();
+        assert_close!(region.gain(127), 1.0, 0.0001);
+        assert_close!(region.gain(0), 0.0, 0.0001);
+        region.apply_opcode("amp_veltrack", "0");
+        region.apply_opcode("volume", "-6");
+        assert_close!(region.gain(10), 0.5012, 0.0001);
+        let mut region = SfzRegion::default();
+        region.apply_opcode("amp_velcurve_64", "0.8");
+        assert_close!(region.gain(32), 0.4, 0.01);
+        assert_close!(region.gain(64), 0.8, 0.0001);
+        assert_close!(region.gain(127), 1.0, 0.0001);
+    }
+    #[test]
+    fn test_release_trigger(){
+        let mut region = SfzRegion::default();
+        assert!(region.trigger.plays_on(false, true));
+        region.apply_opcode("trigger", "release");
+        region.apply_opcode("rt_decay", "6");
+        assert!(region.trigger.plays_on(true, false));
+        assert!(!region.trigger.plays_on(false, false));
+        assert_close!(region.release_attenuation(0.0), 1.0, 0.0001);
+        assert_close!(region.release_attenuation(1.0), 0.5012, 0.0001);
+        region.apply_opcode("trigger", "first");
+        assert!(region.trigger.plays_on(false, false));
+        assert!(!region.trigger.plays_on(false, true));
+        region.apply_opcode("trigger", "legato");
+        assert!(region.trigger.plays_on(false, true));
+    }
+    #[test]
+    fn test_keyswitches(){
+        let headers = sfz_parser::parse(
+            "<global> sw_lokey=c1 sw_hikey=d1 sw_default=c1\n\
+             <group> sw_last=c1 sw_label=Sustain\n\
+             <region> sample=a.wav\n\
+             <group> sw_last=d1 sw_label=Staccato\n\
+             <region> sample=b.wav sw_up=e1", &mut Vec::new());
+        let mut sfz = SfzInstrument::new();
+        for parsed in collect_regions(&headers){
+            sfz.add_region(parsed.region);
+        }
+        assert!(sfz.is_keyswitch(24) && sfz.is_keyswitch(25) && sfz.is_keyswitch(26) && sfz.is_keyswitch(28));
+        assert!(!sfz.is_keyswitch(60));
+        assert_eq!(sfz.default_keyswitch(), Some(24));
+        assert_eq!(sfz.keyswitch_labels(), &[(24, "Sustain".to_string()), (26, "Staccato".to_string())]);
+        let regions = &sfz.regions;
+        assert!(regions[0].is_switched_on(Some(24), |_| false));
+        assert!(!regions[0].is_switched_on(Some(26), |_| false));
+        assert!(regions[1].is_switched_on(Some(26), |_| false));
+        assert!(!regions[1].is_switched_on(Some(26), |key| key == 28));
+    }
+    #[test]
+    fn test_cc(){
+        let mut region = SfzRegion::default();
+        region.apply_opcode("locc1", "64");
+        region.apply_opcode("amplitude_oncc1", "100");
+        region.apply_opcode("pan_oncc10", "-50");
+        region.apply_opcode("cutoff", "1000");
+        region.apply_opcode("cutoff_oncc74", "1200");
+        let mut cc_values = [0.0; 128];
+        assert!(!region.is_in_cc_range(&cc_values));
+        cc_values[1] = 0.5;
+        cc_values[10] = 1.0;
+        cc_values[74] = 1.0;
+        assert!(region.is_in_cc_range(&cc_values));
+        assert_close!(region.cc_gain(&cc_values), 0.5, 0.0001);
+        assert_close!(region.cc_pan(&cc_values), -50.0, 0.0001);
+        assert_close!(region.cc_cutoff(&cc_values).unwrap(), 2000.0, 0.01);
+        let headers = sfz_parser::parse("<control> set_cc1=127 set_cc7=0", &mut Vec::new());
+        assert_eq!(collect_cc_defaults(&headers), vec![(1, 1.0), (7, 0.0)]);
+    }
+    #[test]
+    fn test_crossfade(){
+        let mut region = SfzRegion::default();
+        assert_close!(region.crossfade_gain(0, 0), 1.0, 0.0001);
+        assert_close!(region.crossfade_gain(127, 127), 1.0, 0.0001);
+        region.apply_opcode("xfin_lovel", "40");
+        region.apply_opcode("xfin_hivel", "60");
+        region.apply_opcode("xfout_lokey", "c4");
+        region.apply_opcode("xfout_hikey", "70");
+        assert_close!(region.crossfade_gain(60, 30), 0.0, 0.0001);
+        assert_close!(region.crossfade_gain(60, 50), std::f32::consts::FRAC_1_SQRT_2, 0.0001);
+        assert_close!(region.crossfade_gain(65, 100), std::f32::consts::FRAC_1_SQRT_2, 0.0001);
+        assert_close!(region.crossfade_gain(70, 100), 0.0, 0.0001);
+        region.apply_opcode("xf_velcurve", "gain");
+        assert_close!(region.crossfade_gain(60, 50), 0.5, 0.0001);
+    }
+    #[test]
+    fn test_inheritance(){
+        let headers = sfz_parser::parse(
+            "<global> volume=-3 sample=a.wav\n\
+             <group> lokey=40 hikey=50 tune=10\n\
+             <region> tune=20\n\
+             <region> sample=b.wav\n\
+             <master> pan=50\n\
+             <region> lokey=60\n\
+             <global>\n\
+             <region> sample=c.wav", &mut Vec::new());
+        let regions = collect_regions(&headers);
+        assert_eq!(regions.len(), 4);
+        assert_eq!((regions[0].region.lokey, regions[0].region.hikey), (40, 50));
+        assert_eq!(regions[0].region.tune, 20.0);
+        assert_eq!(regions[0].region.volume, -3.0);
+        assert_eq!(regions[0].sample, "a.wav");
+        assert_eq!(regions[1].region.tune, 10.0);
+        assert_eq!(regions[1].sample, "b.wav");
+        // The master clears the group but keeps the global opcodes
+        assert_eq!((regions[2].region.lokey, regions[2].region.hikey), (60, 127));
+        assert_eq!(regions[2].region.pan, 50.0);
+        assert_eq!(regions[2].region.volume, -3.0);
+        assert_eq!(regions[3].region.volume, 0.0);
+        assert_eq!(regions[3].region.pan, 0.0);
+    }
+    #[test]
+    fn test_round_robin(){
+        let headers = sfz_parser::parse(
+            "<group> seq_length=2\n\
+             <region> seq_position=1 sample=a.wav\n\
+             <region> seq_position=2 sample=b.wav\n\
+             <group>\n\
+             <region> lorand=0 hirand=0.5 sample=c.wav\n\
+             <region> lorand=0.5 hirand=1 sample=d.wav", &mut Vec::new());
+        let mut sfz = SfzInstrument::new();
+        let regions = collect_regions(&headers);
+        sfz.sequences = (0..3).map(|_| AtomicU32::new(0)).collect();
+        for (i, parsed) in regions.into_iter().enumerate(){
+            sfz.add_region(SfzRegion{sample: i, ..parsed.region});
+        }
+        let samples = |random: f32| sfz.regions_for(60, 100, random).map(|(_, region)| region.sample).collect::<Vec<_>>();
+        assert_eq!(samples(0.2), vec![0, 2]);
+        sfz.advance_sequences(60, 100);
+        assert_eq!(samples(0.7), vec![1, 3]);
+        sfz.advance_sequences(60, 100);
+        assert_eq!(samples(1.0), vec![0, 3]);
+    }
+    /// Plays note 60 on the first region of `text`, whose sample is a ramp of 1000 frames
+    /// so every output sample tells which frame the voice is reading
+    fn ramp_engine(text: &str)->SamplerEngine{
+        let regions = collect_regions(&sfz_parser::parse(text, &mut Vec::new()))
+            .into_iter()
+            .map(|parsed| parsed.region)
+            .collect();
+        let ramp = (0..1000).map(|i| (i + 1) as f32 / 1000.0).collect::<Vec<_>>();
+        let sample = Arc::new(SampleBuffer::from_interleaved(&ramp, 1, 48000.0));
+        let mut engine = SamplerEngine::new(48000.0, 2);
+        engine.set_params(EngineParams{
+            attack: 0.0, decay: 0.0, sustain: 1.0, release: 1.0,
+            start_point: 0.0, end_point: 100.0, sus_start: 40.0, sus_end: 60.0,
+            sus_mode: SustainModes::NoLoop, fade_time: 0.0, num_voices: 6,
+            interpolation: Interpolation::Linear,
+        });
+        engine.swap_instrument(LoadedInstrument::Sfz(Arc::new(SfzInstrument::from_regions(regions, vec![sample]))));
+        render(&mut engine, 2000);
+        engine.note_on(60, 1.0);
+        engine
+    }
+    /// Renders `frames` frames and returns the left channel
+    fn render(engine: &mut SamplerEngine, frames: usize)->Vec<f32>{
+        let mut frame = [0.0; 2];
+        (0..frames).map(|_| {engine.process(&mut frame); frame[0]}).collect()
+    }
+    #[test]
+    fn test_loop_modes(){
+        let in_loop = |output: &[f32]| output.iter().all(|sample| *sample > 0.1 && *sample <= 0.1501);
+        // The loop points are ignored and the sample plays through once, even while held
+        let mut engine = ramp_engine("<region> sample=ramp.wav loop_mode=no_loop loop_start=100 loop_end=149");
+        let output = render(&mut engine, 1100);
+        assert!(output[998] > 0.99);
+        assert_eq!(output[1099], 0.0);
+        // Note offs are ignored
+        let mut engine = ramp_engine("<region> sample=ramp.wav loop_mode=one_shot ampeg_release=0.001");
+        engine.note_off(60);
+        let output = render(&mut engine, 1100);
+        assert!((output[500] - 0.501).abs() < 1e-4);
+        assert_eq!(output[1099], 0.0);
+        // Loops through the release
+        let mut engine = ramp_engine("<region> sample=ramp.wav loop_mode=loop_continuous loop_start=100 loop_end=149");
+        assert!(in_loop(&render(&mut engine, 3000)[100..]));
+        engine.note_off(60);
+        // Turned down a little by the release, but still inside the loop
+        assert!(render(&mut engine, 2000).iter().all(|sample| *sample > 0.09 && *sample <= 0.1501));
+        // Loops while the key is held, then plays on to the end of the sample
+        let mut engine = ramp_engine("<region> sample=ramp.wav loop_mode=loop_sustain loop_start=100 loop_end=149");
+        assert!(in_loop(&render(&mut engine, 3000)[100..]));
+        engine.note_off(60);
+        let output = render(&mut engine, 1100);
+        assert!(output.iter().any(|sample| *sample > 0.9));
+        assert_eq!(output[1099], 0.0);
+        // Loop points alone loop continuously
+        let mut engine = ramp_engine("<region> sample=ramp.wav loop_start=100 loop_end=149");
+        assert!(in_loop(&render(&mut engine, 3000)[100..]));
+    }
+    #[test]
+    fn test_out_of_range_points(){
+        let mut region = SfzRegion::default();
+        region.apply_opcode("end", "5000");
+        region.apply_opcode("loop_start", "2000");
+        region.apply_opcode("loop_end", "5000");
+        assert_eq!(region.playback_range(1000), (0, 1000, 999, 1000));
+        region.apply_opcode("offset", "3000");
+        assert_eq!(region.playback_range(1000), (1000, 1000, 999, 1000));
+        // A loop past the end of the sample is squeezed onto its last frame instead of reading beyond it
+        let mut engine = ramp_engine("<region> sample=ramp.wav end=5000 loop_start=2000 loop_end=5000");
+        let output = render(&mut engine, 3000);
+        assert!(output[1000..].iter().all(|sample| (sample - 1.0).abs() < 1e-4));
+        // An offset past the end plays nothing
+        let mut engine = ramp_engine("<region> sample=ramp.wav offset=3000");
+        assert!(render(&mut engine, 100).iter().all(|sample| *sample == 0.0));
+    }
+}
@@ -0,0 +1,208 @@
+/// The kinds of header an sfz file can contain
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HeaderKind{
+    Control,
+    Global,
+    Master,
+    Group,
+    Region,
+    Other,
+}
+
+impl HeaderKind{
+    /// Returns None for names that aren't sfz headers
+    fn from_name(name: &str)->Option<Self>{
+        match name{
+            "control" => Some(HeaderKind::Control),
+            "global" => Some(HeaderKind::Global),
+            "master" => Some(HeaderKind::Master),
+            "group" => Some(HeaderKind::Group),
+            "region" => Some(HeaderKind::Region),
+            "curve" | "effect" | "midi" | "sample" => Some(HeaderKind::Other),
+            _ => None,
+        }
+    }
+}
+
+/// A header and the opcodes written after it, in file order
+#[derive(Clone, Debug)]
+pub struct SfzHeader{
+    pub kind: HeaderKind,
+    pub opcodes: Vec<(String, String)>,
+}
+
+/// Splits the text of an sfz file into its headers.
+///
+/// Unknown headers and opcodes written outside a header are skipped
+/// and described in `errors`
+pub fn parse(text: &str, errors: &mut Vec<String>)->Vec<SfzHeader>{
+    let mut headers = Vec::<SfzHeader>::new();
+    for line in strip_comments(text).lines(){
+        let mut rest = line.trim_start();
+        while !rest.is_empty(){
+            if let Some(header) = rest.strip_prefix('<'){
+                // An unclosed header ends at the first space
+                let (end, closed) = match header.find('>'){
+                    Some(end) => (end, true),
+                    None => (header.find(char::is_whitespace).unwrap_or(header.len()), false),
+                };
+                let name = header[..end].trim();
+                match HeaderKind::from_name(name){
+                    Some(kind) => headers.push(SfzHeader{kind, opcodes: Vec::new()}),
+                    None =>{
+                        errors.push(format!("Unknown header <{}>", name));
+                        // Its opcodes go to a header that is never used
+                        headers.push(SfzHeader{kind: HeaderKind::Other, opcodes: Vec::new()});
+                    }
+                }
+                if closed{
+                    rest = &header[end + 1..];
+                }else{
+                    errors.push(format!("Header <{} is missing its closing >", name));
+                    rest = &header[end..];
+                }
+            }else{
+                let token_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                match rest[..token_end].find('='){
+                    Some(eq) =>{
+                        let name = &rest[..eq];
+                        let value = &rest[eq + 1..];
+                        let value_end = find_value_end(value);
+                        match headers.last_mut(){
+                            Some(header) => header.opcodes.push((name.to_string(), value[..value_end].trim().to_string())),
+                            None => errors.push(format!("Opcode {} is outside of a header", name)),
+                        }
+                        rest = &value[value_end..];
+                    },
+                    // Not an opcode, skip the word
+                    None => rest = &rest[token_end..],
+                }
+            }
+            rest = rest.trim_start();
+        }
+    }
+    headers
+}
+
+/// Finds where an opcode's value ends. Values such as sample paths may contain spaces,
+/// so a value runs until the next header or the next word that looks like `name=`
+fn find_value_end(value: &str)->usize{
+    let mut previous_is_space = false;
+    for (i, c) in value.char_indices(){
+        if c == '<'{
+            return i;
+        }
+        if previous_is_space && !c.is_whitespace() && is_opcode_start(&value[i..]){
+            return i;
+        }
+        previous_is_space = c.is_whitespace();
+    }
+    value.len()
+}
+
+/// Returns whether the text starts with an opcode name followed by `=`
+pub fn is_opcode_start(text: &str)->bool{
+    let name_end = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(text.len());
+    name_end > 0 && text[name_end..].starts_with('=')
+}
+
+/// Removes `//` line comments and `/* */` block comments, keeping the line breaks
+pub fn strip_comments(text: &str)->String{
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next(){
+        if c == '/' && chars.peek() == Some(&'/'){
+            while let Some(next) = chars.peek(){
+                if *next == '\n'{
+                    break;
+                }
+                chars.next();
+            }
+        }else if c == '/' && chars.peek() == Some(&'*'){
+            chars.next();
+            let mut previous = ' ';
+            for next in chars.by_ref(){
+                if next == '\n'{
+                    stripped.push('\n');
+                }
+                if previous == '*' && next == '/'{
+                    break;
+                }
+                previous = next;
+            }
+        }else{
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// Parses a midi note given either as a number or as a name such as `c4` or `f#3`.
+/// Middle C (c4) is note 60
+pub fn parse_note(value: &str)->Option<u8>{
+    if let Ok(number) = value.parse::<i32>(){
+        return u8::try_from(number).ok().filter(|note| *note <= 127);
+    }
+    let value = value.to_ascii_lowercase();
+    let mut chars = value.chars();
+    let mut semitone: i32 = match chars.next()?{
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let mut octave = chars.as_str();
+    if let Some(rest) = octave.strip_prefix('#'){
+        semitone += 1;
+        octave = rest;
+    }else if let Some(rest) = octave.strip_prefix('b'){
+        semitone -= 1;
+        octave = rest;
+    }
+    let note = (octave.parse::<i32>().ok()? + 1) * 12 + semitone;
+    u8::try_from(note).ok().filter(|note| *note <= 127)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    #[test]
+    fn test_parse(){
+        let text = "// A piano\n<control> default_path=Samples\\Piano/\n\
+                    <region> sample=C4 soft.wav lokey=c4 hikey=61 /* comment */ pitch_keycenter=60\n\
+                    <region>sample=D4.wav<region> key=62";
+        let mut errors = Vec::new();
+        let headers = parse(text, &mut errors);
+        assert!(errors.is_empty());
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers[0].kind, HeaderKind::Control);
+        assert_eq!(headers[0].opcodes, vec![("default_path".to_string(), "Samples\\Piano/".to_string())]);
+        assert_eq!(headers[1].kind, HeaderKind::Region);
+        assert_eq!(headers[1].opcodes[0], ("sample".to_string(), "C4 soft.wav".to_string()));
+        assert_eq!(headers[1].opcodes[1], ("lokey".to_string(), "c4".to_string()));
+        assert_eq!(headers[1].opcodes[3], ("pitch_keycenter".to_string(), "60".to_string()));
+        assert_eq!(headers[2].opcodes, vec![("sample".to_string(), "D4.wav".to_string())]);
+        assert_eq!(headers[3].opcodes, vec![("key".to_string(), "62".to_string())]);
+        let headers = parse("volume=1 <regoin> key=1 <region key=2", &mut errors);
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert_eq!(headers[0].kind, HeaderKind::Other);
+        assert_eq!(headers[1].kind, HeaderKind::Region);
+        assert_eq!(headers[1].opcodes, vec![("key".to_string(), "2".to_string())]);
+    }
+    #[test]
+    fn test_parse_note(){
+        assert_eq!(parse_note("60"), Some(60));
+        assert_eq!(parse_note("c4"), Some(60));
+        assert_eq!(parse_note("F#3"), Some(54));
+        assert_eq!(parse_note("db4"), Some(61));
+        assert_eq!(parse_note("c-1"), Some(0));
+        assert_eq!(parse_note("128"), None);
+        assert_eq!(parse_note("x"), None);
+    }
+}
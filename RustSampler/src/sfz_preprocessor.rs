@@ -0,0 +1,148 @@
+use crate::sfz_parser;
+use sfz_parser::{is_opcode_start, strip_comments};
+use std::{fs, io, path::Path};
+
+/// How deep `#include`s can nest before the preprocessor assumes a file includes itself
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Reads an sfz file and expands its preprocessor directives, ready for `sfz_parser::parse`.
+///
+/// Problems with the directives are added to `errors` and the rest of the file is still read
+pub fn preprocess_file(path: &Path, errors: &mut Vec<String>)->io::Result<String>{
+    let text = fs::read_to_string(path)?;
+    let sfz_dir = path.parent().unwrap_or(Path::new(""));
+    Ok(preprocess(&text, sfz_dir, errors))
+}
+
+/// Expands the preprocessor directives of sfz text:
+///
+/// - `#include "file.sfz"` inserts another file, found relative to `sfz_dir`,
+///   the folder of the top level sfz file
+/// - `#define $NAME value` replaces `$NAME` with `value` in the rest of the text,
+///   including the included files
+/// - A line ending in `\` continues on the next line, unless the next line starts
+///   a header, an opcode or a directive. This keeps paths ending in `\` working
+pub fn preprocess(text: &str, sfz_dir: &Path, errors: &mut Vec<String>)->String{
+    let mut output = String::with_capacity(text.len());
+    let mut defines = Vec::new();
+    expand(text, sfz_dir, &mut defines, 0, errors, &mut output);
+    output
+}
+
+/// Expands one file's text into `output`, following its includes
+fn expand(
+    text: &str,
+    sfz_dir: &Path,
+    defines: &mut Vec<(String, String)>,
+    depth: usize,
+    errors: &mut Vec<String>,
+    output: &mut String,
+){
+    for line in join_continued_lines(&strip_comments(text)){
+        let trimmed = line.trim();
+        if let Some(define) = trimmed.strip_prefix("#define"){
+            let define = define.trim();
+            let (name, value) = define.split_once(char::is_whitespace).unwrap_or((define, ""));
+            if !name.starts_with('$') || name.len() < 2{
+                errors.push(format!("Bad #define: {}", trimmed));
+                continue;
+            }
+            defines.retain(|(defined, _)| defined != name);
+            let value = substitute(value.trim(), defines);
+            defines.push((name.to_string(), value));
+            // Longest names first, so $NOTE doesn't replace the start of $NOTE_HIGH
+            defines.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+        }else if let Some(include) = trimmed.strip_prefix("#include"){
+            let include = substitute(include.trim(), defines);
+            let file_name = include.trim_matches('"').replace('\\', "/");
+            if depth >= MAX_INCLUDE_DEPTH{
+                errors.push(format!("Too many nested includes at {}", file_name));
+                continue;
+            }
+            match fs::read_to_string(sfz_dir.join(&file_name)){
+                Ok(included) => expand(&included, sfz_dir, defines, depth + 1, errors, output),
+                Err(e) => errors.push(format!("Could not include {}: {}", file_name, e)),
+            }
+        }else if trimmed.starts_with('#'){
+            errors.push(format!("Unknown directive: {}", trimmed));
+        }else{
+            output.push_str(&substitute(&line, defines));
+            output.push('\n');
+        }
+    }
+}
+
+/// Replaces the defined names in a line with their values
+fn substitute(line: &str, defines: &[(String, String)])->String{
+    let mut line = line.to_string();
+    if line.contains('$'){
+        for (name, value) in defines.iter(){
+            line = line.replace(name.as_str(), value);
+        }
+    }
+    line
+}
+
+/// Splits text into lines, joining lines that end in `\` with the line after them
+fn join_continued_lines(text: &str)->Vec<String>{
+    let mut lines = Vec::<String>::new();
+    let mut continued = false;
+    for line in text.lines(){
+        let next = line.trim_start();
+        let starts_new = next.is_empty() || next.starts_with('<') || next.starts_with('#') || is_opcode_start(next);
+        match lines.last_mut(){
+            Some(last) if continued && !starts_new =>{
+                last.pop();
+                last.push_str(next);
+            },
+            _ => lines.push(line.to_string()),
+        }
+        continued = lines.last().map_or(false, |last| last.trim_end().ends_with('\\'));
+        if continued{
+            let last = lines.last_mut().unwrap();
+            last.truncate(last.trim_end().len());
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::{path::PathBuf, sync::atomic::{AtomicUsize, Ordering}};
+    /// Returns a fresh directory so parallel test runs never share files
+    fn unique_dir()->PathBuf{
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("sfz_preprocessor_test_{}_{}", std::process::id(), count))
+    }
+    #[test]
+    fn test_preprocess(){
+        let dir = unique_dir();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("keys.sfz"), "#define $HI 64\n<region> sample=$NAME.wav hikey=$HI").unwrap();
+        let text = "#define $NAME piano\n\
+                    #define $NAME_SOFT soft\n\
+                    // #include \"commented.sfz\"\n\
+                    <control> default_path=Samples\\\n\
+                    <group> lokey=60 sample=$NAME_SOFT \\\n  \
+                    layer.wav\n\
+                    #include \"keys.sfz\"\n\
+                    <region> key=$HI\n\
+                    #include \"missing.sfz\"";
+        let mut errors = Vec::new();
+        let headers = sfz_parser::parse(&preprocess(text, &dir, &mut errors), &mut errors);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].contains("missing.sfz"));
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers[0].opcodes[0].1, "Samples\\");
+        assert_eq!(headers[1].opcodes, vec![
+            ("lokey".to_string(), "60".to_string()),
+            ("sample".to_string(), "soft layer.wav".to_string()),
+        ]);
+        assert_eq!(headers[2].opcodes[0].1, "piano.wav");
+        assert_eq!(headers[2].opcodes[1].1, "64");
+        assert_eq!(headers[3].opcodes[0].1, "64");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}